@@ -13,7 +13,7 @@ pub async fn add_new_user(
     if let Err(error) = room_collection
         .update_one(
             doc! { "room_id": room_id.clone() },
-            doc! { "$addToSet": {"users": user.clone() }},
+            doc! { "$addToSet": {"users": user }, "$set": {"archived": false} },
         )
         .await
     {
@@ -0,0 +1,34 @@
+use mongodb::Database;
+use mongodb::bson::doc;
+
+use crate::db::db::Room;
+
+pub async fn ban_user(
+    room_id: String,
+    user: mongodb::bson::oid::ObjectId,
+    db_conn: Database,
+) -> Result<(), anyhow::Error> {
+    let room_collection = db_conn.collection::<Room>("rooms");
+
+    if let Err(error) = room_collection
+        .update_one(
+            doc! { "room_id": room_id.clone() },
+            doc! {
+                "$addToSet": { "banned": user },
+                "$pull": { "users": user, "co_hosts": user },
+            },
+        )
+        .await
+    {
+        log::error!(
+            "Failed to ban user from room. Failed with error: {:?}",
+            error
+        );
+
+        return Err(anyhow::Error::msg("Failed to ban user from room"));
+    };
+
+    log::info!("User {} banned from room with ID: {}", user, room_id);
+
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use mongodb::Database;
+use mongodb::bson::doc;
+
+use crate::db::db::Room;
+
+// Unlike leaving, a kick also takes away any role the member had
+pub async fn kick_user(
+    room_id: String,
+    user: mongodb::bson::oid::ObjectId,
+    db_conn: Database,
+) -> Result<(), anyhow::Error> {
+    let room_collection = db_conn.collection::<Room>("rooms");
+
+    if let Err(error) = room_collection
+        .update_one(
+            doc! { "room_id": room_id.clone() },
+            doc! { "$pull": { "users": user, "co_hosts": user } },
+        )
+        .await
+    {
+        log::error!(
+            "Failed to kick user from room. Failed with error: {:?}",
+            error
+        );
+
+        return Err(anyhow::Error::msg("Failed to kick user from room"));
+    };
+
+    log::info!("User {} kicked from room with ID: {}", user, room_id);
+
+    Ok(())
+}
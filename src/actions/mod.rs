@@ -1,6 +1,10 @@
 use serde::{Deserialize, Serialize};
 
 pub mod add_user;
+pub mod ban_user;
+pub mod kick_user;
+pub mod remove_user;
+pub mod roles;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Platform {
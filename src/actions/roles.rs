@@ -0,0 +1,96 @@
+use mongodb::Database;
+use mongodb::bson::{doc, oid::ObjectId, to_bson};
+
+use crate::db::db::{PlaybackControl, Role, Room};
+
+pub async fn get_room(room_id: String, db_conn: Database) -> Result<Room, anyhow::Error> {
+    let room_collection = db_conn.collection::<Room>("rooms");
+
+    match room_collection
+        .find_one(doc! { "room_id": room_id.clone() })
+        .await
+    {
+        Ok(Some(room)) => Ok(room),
+        Ok(None) => Err(anyhow::Error::msg(format!("Room {} not found", room_id))),
+        Err(error) => {
+            log::error!("Failed to fetch room. Failed with error: {:?}", error);
+
+            Err(anyhow::Error::msg("Failed to fetch room"))
+        }
+    }
+}
+
+pub async fn set_role(
+    room_id: String,
+    user: ObjectId,
+    role: Role,
+    db_conn: Database,
+) -> Result<(), anyhow::Error> {
+    let room_collection = db_conn.collection::<Room>("rooms");
+
+    let update = match role {
+        Role::Host => doc! {
+            "$set": { "host": user },
+            "$pull": { "co_hosts": user },
+        },
+        Role::CoHost => doc! { "$addToSet": { "co_hosts": user } },
+        Role::Viewer => doc! { "$pull": { "co_hosts": user } },
+    };
+
+    if let Err(error) = room_collection
+        .update_one(doc! { "room_id": room_id.clone() }, update)
+        .await
+    {
+        log::error!(
+            "Failed to update member role. Failed with error: {:?}",
+            error
+        );
+
+        return Err(anyhow::Error::msg("Failed to update member role"));
+    };
+
+    log::info!(
+        "User {} is now {:?} in room with ID: {}",
+        user,
+        role,
+        room_id
+    );
+
+    Ok(())
+}
+
+// The previous host stays on as a co-host so they keep playback control
+pub async fn transfer_host(
+    room_id: String,
+    from: ObjectId,
+    to: ObjectId,
+    db_conn: Database,
+) -> Result<(), anyhow::Error> {
+    set_role(room_id.clone(), to, Role::Host, db_conn.clone()).await?;
+    set_role(room_id, from, Role::CoHost, db_conn).await
+}
+
+pub async fn set_playback_control(
+    room_id: String,
+    control: PlaybackControl,
+    db_conn: Database,
+) -> Result<(), anyhow::Error> {
+    let room_collection = db_conn.collection::<Room>("rooms");
+
+    if let Err(error) = room_collection
+        .update_one(
+            doc! { "room_id": room_id.clone() },
+            doc! { "$set": { "control": to_bson(&control).unwrap() } },
+        )
+        .await
+    {
+        log::error!(
+            "Failed to update playback control. Failed with error: {:?}",
+            error
+        );
+
+        return Err(anyhow::Error::msg("Failed to update playback control"));
+    };
+
+    Ok(())
+}
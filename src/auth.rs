@@ -0,0 +1,74 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::handshake::server::Request;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionClaims {
+    pub sub: String,
+    pub exp: u64,
+}
+
+pub fn issue_session_token(
+    user_id: &str,
+    secret: &str,
+    ttl_secs: u64,
+) -> Result<String, anyhow::Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let claims = SessionClaims {
+        sub: user_id.to_string(),
+        exp: now + ttl_secs,
+    };
+
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .map_err(|e| {
+        log::error!("Failed to sign session token. Failed with error: {:?}", e);
+        anyhow::Error::msg("Failed to sign session token")
+    })
+}
+
+// Returns the user id the token was issued for
+pub fn verify_session_token(token: &str, secret: &str) -> Result<String, anyhow::Error> {
+    decode::<SessionClaims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::default(),
+    )
+    .map(|data| data.claims.sub)
+    .map_err(|e| {
+        log::error!("Invalid session token. Failed with error: {:?}", e);
+        anyhow::Error::msg("Invalid session token")
+    })
+}
+
+// Clients may pass the token as `?token=` or as an `Authorization: Bearer` header
+pub fn token_from_request(req: &Request) -> Option<String> {
+    let from_query = req.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+            .map(|token| token.to_string())
+    });
+
+    from_query.or_else(|| bearer_token(req.headers().get("authorization")?.to_str().ok()?))
+}
+
+// Resolves the user behind an `Authorization: Bearer` header value
+pub fn user_from_authorization(header_value: &str, secret: &str) -> Option<String> {
+    verify_session_token(&bearer_token(header_value)?, secret).ok()
+}
+
+pub fn bearer_token(header_value: &str) -> Option<String> {
+    header_value
+        .strip_prefix("Bearer ")
+        .map(|token| token.trim().to_string())
+}
@@ -0,0 +1,23 @@
+use anyhow::Error;
+use lofi_party::db::{db::connect_to_db, migrate::migrate_embedded_messages};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    dotenv::dotenv().ok();
+    tracing_subscriber::fmt::init();
+
+    let config = lofi_party::config::Config::get_config();
+
+    let (db, _, _) = connect_to_db(config.mongodb_url).await?;
+
+    let report = migrate_embedded_messages(db).await?;
+
+    log::info!(
+        "Moved {} messages out of {} rooms, skipped {} rooms",
+        report.messages,
+        report.rooms,
+        report.skipped_rooms
+    );
+
+    Ok(())
+}
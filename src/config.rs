@@ -1,4 +1,21 @@
-use std::env;
+use std::{env, str::FromStr};
+
+use crate::services::outbound::SlowConsumerPolicy;
+
+const DEFAULT_SYNC_TTL_SECS: u64 = 60 * 60 * 48;
+const DEFAULT_ROOM_IDLE_TIMEOUT_SECS: u64 = 60 * 60 * 24;
+const DEFAULT_REAPER_INTERVAL_SECS: u64 = 60 * 5;
+const DEFAULT_SESSION_TTL_SECS: u64 = 60 * 60 * 24 * 7;
+const DEFAULT_TYPING_TTL_SECS: u64 = 6;
+const DEFAULT_OUTBOUND_QUEUE_SIZE: usize = 256;
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 16;
+const DEFAULT_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_DEDUPE_WINDOW_SECS: u64 = 60 * 5;
+const DEFAULT_EVENT_LOG_SIZE: usize = 500;
+const DEFAULT_SESSION_GRACE_SECS: u64 = 30;
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 15;
+const DEFAULT_HEARTBEAT_MAX_MISSED: u32 = 3;
 
 #[derive(Debug, Default)]
 pub struct Config {
@@ -6,6 +23,23 @@ pub struct Config {
     pub ws_port: String,
     pub redis_url: String,
     pub mongodb_url: String,
+    pub sync_ttl_secs: u64,
+    pub room_idle_timeout_secs: u64,
+    pub reaper_interval_secs: u64,
+    pub archive_idle_rooms: bool,
+    pub session_secret: String,
+    pub session_ttl_secs: u64,
+    pub typing_ttl_secs: u64,
+    pub outbound_queue_size: usize,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub handshake_timeout_secs: u64,
+    pub dedupe_window_secs: u64,
+    pub event_log_size: usize,
+    pub session_grace_secs: u64,
+    pub heartbeat_interval_secs: u64,
+    pub heartbeat_max_missed: u32,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -16,6 +50,8 @@ enum ConfigError {
     InvalidRedisUrl,
     #[error("Error: Invalid MongoDB URL")]
     InvalidDBUrl,
+    #[error("Error: SESSION_SECRET must be set")]
+    MissingSessionSecret,
 }
 
 impl Config {
@@ -31,11 +67,91 @@ impl Config {
         let mongodb_url =
             env::var("MONGODB_URL").unwrap_or_else(|_| ConfigError::InvalidDBUrl.to_string());
 
+        let room_idle_timeout_secs =
+            parse_env("ROOM_IDLE_TIMEOUT_SECS", DEFAULT_ROOM_IDLE_TIMEOUT_SECS);
+
+        let reaper_interval_secs = parse_env("REAPER_INTERVAL_SECS", DEFAULT_REAPER_INTERVAL_SECS);
+
+        // The reaper finds idle rooms through their sync entries, so those must outlive the idle cutoff
+        let min_sync_ttl_secs = room_idle_timeout_secs + reaper_interval_secs + 1;
+        let mut sync_ttl_secs = parse_env("SYNC_TTL_SECS", DEFAULT_SYNC_TTL_SECS);
+
+        if sync_ttl_secs < min_sync_ttl_secs {
+            log::warn!(
+                "SYNC_TTL_SECS must exceed the idle timeout plus the reaper interval, using {}",
+                min_sync_ttl_secs
+            );
+            sync_ttl_secs = min_sync_ttl_secs;
+        }
+
+        let archive_idle_rooms = parse_env("ARCHIVE_IDLE_ROOMS", false);
+
+        // Signing with a guessable fallback would let anyone mint tokens
+        let session_secret = env::var("SESSION_SECRET")
+            .unwrap_or_else(|_| panic!("{}", ConfigError::MissingSessionSecret));
+
+        let session_ttl_secs = parse_env("SESSION_TTL_SECS", DEFAULT_SESSION_TTL_SECS);
+
+        // Clients keep typing alive by resending it, so a crashed client stops showing after this
+        let typing_ttl_secs = parse_env("TYPING_TTL_SECS", DEFAULT_TYPING_TTL_SECS);
+
+        let outbound_queue_size = parse_env("OUTBOUND_QUEUE_SIZE", DEFAULT_OUTBOUND_QUEUE_SIZE);
+
+        // One of drop, coalesce or disconnect
+        let slow_consumer_policy = parse_env("SLOW_CONSUMER_POLICY", SlowConsumerPolicy::default());
+
+        let max_connections = parse_env("MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS);
+
+        let max_connections_per_ip =
+            parse_env("MAX_CONNECTIONS_PER_IP", DEFAULT_MAX_CONNECTIONS_PER_IP);
+
+        let handshake_timeout_secs =
+            parse_env("HANDSHAKE_TIMEOUT_SECS", DEFAULT_HANDSHAKE_TIMEOUT_SECS);
+
+        // How long a resent event with the same request id is treated as a retry
+        let dedupe_window_secs = parse_env("DEDUPE_WINDOW_SECS", DEFAULT_DEDUPE_WINDOW_SECS);
+
+        // Clients further behind than this many broadcasts get a snapshot instead of a replay
+        let event_log_size = parse_env("EVENT_LOG_SIZE", DEFAULT_EVENT_LOG_SIZE);
+
+        // How long a dropped member stays in the room waiting to resume before others see them leave
+        let session_grace_secs = parse_env("SESSION_GRACE_SECS", DEFAULT_SESSION_GRACE_SECS);
+
+        let heartbeat_interval_secs =
+            parse_env("HEARTBEAT_INTERVAL_SECS", DEFAULT_HEARTBEAT_INTERVAL_SECS);
+
+        // A client that misses this many pings in a row is treated as gone
+        let heartbeat_max_missed = parse_env("HEARTBEAT_MAX_MISSED", DEFAULT_HEARTBEAT_MAX_MISSED);
+
         Self {
             http_port,
             ws_port,
             redis_url,
             mongodb_url,
+            sync_ttl_secs,
+            room_idle_timeout_secs,
+            reaper_interval_secs,
+            archive_idle_rooms,
+            session_secret,
+            session_ttl_secs,
+            typing_ttl_secs,
+            outbound_queue_size,
+            slow_consumer_policy,
+            max_connections,
+            max_connections_per_ip,
+            handshake_timeout_secs,
+            dedupe_window_secs,
+            event_log_size,
+            session_grace_secs,
+            heartbeat_interval_secs,
+            heartbeat_max_missed,
         }
     }
 }
+
+fn parse_env<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse::<T>().ok())
+        .unwrap_or(default)
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use anyhow::Error;
 use mongodb::{Client, Collection, Database, IndexModel, options::IndexOptions};
 use serde::{Deserialize, Serialize};
@@ -13,8 +15,45 @@ pub struct User {
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Message {
+    #[serde(rename = "_id")]
+    pub id: Option<mongodb::bson::oid::ObjectId>,
+    pub room_id: String,
     pub user_id: mongodb::bson::oid::ObjectId,
     pub message: String,
+    pub created_at: mongodb::bson::DateTime,
+    #[serde(default)]
+    pub edited_at: Option<mongodb::bson::DateTime>,
+    #[serde(default)]
+    pub deleted_at: Option<mongodb::bson::DateTime>,
+    // emoji -> users who reacted with it
+    #[serde(default)]
+    pub reactions: HashMap<String, Vec<mongodb::bson::oid::ObjectId>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VideoReaction {
+    #[serde(rename = "_id")]
+    pub id: Option<mongodb::bson::oid::ObjectId>,
+    pub room_id: String,
+    pub user_id: mongodb::bson::oid::ObjectId,
+    pub emoji: String,
+    // Playback position in seconds when the reaction arrived
+    pub position: f32,
+    pub created_at: mongodb::bson::DateTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    Host,
+    CoHost,
+    Viewer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PlaybackControl {
+    HostOnly,
+    #[default]
+    Everyone,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -23,8 +62,51 @@ pub struct Room {
     pub id: mongodb::bson::oid::ObjectId,
     pub room_id: String,
     pub users: Vec<mongodb::bson::oid::ObjectId>,
-    pub messages: Vec<Message>,
     pub platform: String,
+    #[serde(default)]
+    pub archived: bool,
+    #[serde(default)]
+    pub host: Option<mongodb::bson::oid::ObjectId>,
+    #[serde(default)]
+    pub co_hosts: Vec<mongodb::bson::oid::ObjectId>,
+    #[serde(default)]
+    pub control: PlaybackControl,
+    #[serde(default)]
+    pub banned: Vec<mongodb::bson::oid::ObjectId>,
+}
+
+impl Room {
+    pub fn role_of(&self, user: &mongodb::bson::oid::ObjectId) -> Role {
+        if self.host.as_ref() == Some(user) {
+            Role::Host
+        } else if self.co_hosts.contains(user) {
+            Role::CoHost
+        } else {
+            Role::Viewer
+        }
+    }
+
+    // Members may only act on members ranked below them
+    pub fn can_moderate(
+        &self,
+        actor: &mongodb::bson::oid::ObjectId,
+        target: &mongodb::bson::oid::ObjectId,
+    ) -> bool {
+        let rank = |role: Role| match role {
+            Role::Host => 2,
+            Role::CoHost => 1,
+            Role::Viewer => 0,
+        };
+
+        rank(self.role_of(actor)) > rank(self.role_of(target))
+    }
+
+    pub fn can_control_playback(&self, user: &mongodb::bson::oid::ObjectId) -> bool {
+        match self.control {
+            PlaybackControl::Everyone => true,
+            PlaybackControl::HostOnly => self.role_of(user) != Role::Viewer,
+        }
+    }
 }
 
 pub async fn connect_to_db(
@@ -36,6 +118,8 @@ pub async fn connect_to_db(
 
     let users = db.collection::<User>("users");
     let rooms = db.collection::<Room>("rooms");
+    let messages = db.collection::<Message>("messages");
+    let video_reactions = db.collection::<VideoReaction>("video_reactions");
 
     let options = IndexOptions::builder().unique(true).build();
 
@@ -49,6 +133,14 @@ pub async fn connect_to_db(
         .options(options)
         .build();
 
+    let message_model = IndexModel::builder()
+        .keys(mongodb::bson::doc! { "room_id": 1, "created_at": 1 })
+        .build();
+
+    let video_reaction_model = IndexModel::builder()
+        .keys(mongodb::bson::doc! { "room_id": 1, "position": 1 })
+        .build();
+
     if let Err(err) = users.create_index(user_model).await {
         log::error!("Failed to create index on user. Failed with err: {:?}", err);
 
@@ -61,5 +153,25 @@ pub async fn connect_to_db(
         return Err(anyhow::Error::msg("Failed to create index on room"));
     };
 
+    if let Err(err) = messages.create_index(message_model).await {
+        log::error!(
+            "Failed to create index on message. Failed with error: {:?}",
+            err
+        );
+
+        return Err(anyhow::Error::msg("Failed to create index on message"));
+    };
+
+    if let Err(err) = video_reactions.create_index(video_reaction_model).await {
+        log::error!(
+            "Failed to create index on video reaction. Failed with error: {:?}",
+            err
+        );
+
+        return Err(anyhow::Error::msg(
+            "Failed to create index on video reaction",
+        ));
+    };
+
     Ok((db, users, rooms))
 }
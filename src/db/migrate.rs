@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+use futures_util::TryStreamExt;
+use mongodb::{
+    Database,
+    bson::{DateTime, Document, doc, oid::ObjectId, to_document},
+};
+
+use crate::db::db::Message;
+
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub rooms: u64,
+    pub messages: u64,
+    // Rooms left untouched because some of their messages could not be read
+    pub skipped_rooms: u64,
+}
+
+// Moves chat messages embedded in `rooms.messages` into the `messages` collection.
+// Embedded messages carry no timestamp, so they are spaced a millisecond apart from the
+// room's creation time to keep their order. Each message is upserted on its room and position,
+// so a run that stops halfway can be repeated, and a room is only unset once all of them are in.
+pub async fn migrate_embedded_messages(db: Database) -> Result<MigrationReport, anyhow::Error> {
+    let room_collection = db.collection::<Document>("rooms");
+    let message_collection = db.collection::<Document>("messages");
+
+    let mut report = MigrationReport::default();
+
+    let mut rooms = room_collection
+        .find(doc! { "messages.0": { "$exists": true } })
+        .await
+        .map_err(|e| {
+            log::error!(
+                "Failed to fetch rooms to migrate. Failed with error: {:?}",
+                e
+            );
+            anyhow::Error::msg("Failed to fetch rooms to migrate")
+        })?;
+
+    while let Some(room) = rooms.try_next().await? {
+        let id = room.get_object_id("_id")?;
+        let room_id = room.get_str("room_id")?.to_string();
+        let created_at = id.timestamp().timestamp_millis();
+
+        let embedded_messages = room.get_array("messages")?;
+        let mut messages = Vec::with_capacity(embedded_messages.len());
+
+        for (index, embedded) in embedded_messages.iter().enumerate() {
+            let parsed = embedded.as_document().and_then(|embedded| {
+                Some(Message {
+                    id: Some(ObjectId::new()),
+                    room_id: room_id.clone(),
+                    user_id: embedded.get_object_id("user_id").ok()?,
+                    message: embedded.get_str("message").ok()?.to_string(),
+                    created_at: DateTime::from_millis(created_at + index as i64),
+                    edited_at: None,
+                    deleted_at: None,
+                    reactions: HashMap::new(),
+                })
+            });
+
+            match parsed {
+                Some(message) => messages.push((index, message)),
+                None => log::error!(
+                    "Unreadable message {} in room with ID: {}: {:?}",
+                    index,
+                    room_id,
+                    embedded
+                ),
+            }
+        }
+
+        // Unsetting now would lose the unreadable ones, they stay embedded until fixed by hand
+        if messages.len() != embedded_messages.len() {
+            log::error!(
+                "Skipping room with ID: {}, {} of its messages could not be read",
+                room_id,
+                embedded_messages.len() - messages.len()
+            );
+            report.skipped_rooms += 1;
+            continue;
+        }
+
+        for (index, message) in &messages {
+            message_collection
+                .update_one(
+                    doc! { "room_id": room_id.clone(), "migrated_index": *index as i64 },
+                    doc! { "$setOnInsert": to_document(message)? },
+                )
+                .upsert(true)
+                .await
+                .map_err(|e| {
+                    log::error!(
+                        "Failed to insert messages of room: {}. Failed with error: {:?}",
+                        room_id,
+                        e
+                    );
+                    anyhow::Error::msg("Failed to insert migrated messages")
+                })?;
+        }
+
+        room_collection
+            .update_one(doc! { "_id": id }, doc! { "$unset": { "messages": "" } })
+            .await?;
+
+        log::info!(
+            "Migrated {} messages out of room with ID: {}",
+            messages.len(),
+            room_id
+        );
+
+        report.rooms += 1;
+        report.messages += messages.len() as u64;
+    }
+
+    Ok(report)
+}
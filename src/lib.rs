@@ -1,24 +1,53 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Instant,
+};
 
 use futures_util::stream::SplitSink;
 use mongodb::Database;
 use tokio::net::TcpStream;
 use tokio_tungstenite::{WebSocketStream, tungstenite::Message};
 
+use crate::config::Config;
+use crate::services::{
+    dedupe::DedupeStore, event_log::EventLog, outbound::ClientHandle, pubsub::RoomPubSub,
+    session::SessionStore, sync_store::SyncStore,
+};
 use crate::ws_conn::SyncInfo;
 
 pub mod actions;
+pub mod auth;
 pub mod config;
 pub mod db;
 pub mod services;
 pub mod ws_conn;
 
 pub type Tx = SplitSink<WebSocketStream<TcpStream>, Message>;
-pub type RoomUserMap = Arc<tokio::sync::RwLock<HashMap<String, HashMap<String, Arc<tokio::sync::RwLock<Tx>>>>>>; //room -> user -> WebsocketStream
-pub type RoomSync = Arc<tokio::sync::RwLock<HashMap<String, SyncInfo>>>; //should also delete ones which are last updated around a day ago
+pub type RoomUserMap = Arc<tokio::sync::RwLock<HashMap<String, HashMap<String, ClientHandle>>>>; //room -> user -> outbound queue
+pub type RoomBuffering = Arc<tokio::sync::RwLock<HashMap<String, BufferingState>>>; //room -> members currently buffering
+pub type RoomPresence = Arc<tokio::sync::RwLock<HashMap<String, HashMap<String, Instant>>>>; //room -> user -> last typing keepalive
+pub type RoomSync = Arc<dyn SyncStore>; //redis entries expire via TTL, memory fallback otherwise
+pub type RoomDedupe = Arc<dyn DedupeStore>; //user and request id -> original ack
+pub type RoomEventLog = Arc<dyn EventLog>; //room -> recent broadcasts by sequence number
+pub type RoomSessions = Arc<dyn SessionStore>; //session id -> room membership, shared through redis like the event log
+
+#[derive(Debug, Default)]
+pub struct BufferingState {
+    pub users: HashSet<String>,
+    pub resume_on_ready: bool,
+}
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: Database,
     pub room_sync: RoomSync,
+    pub room_buffering: RoomBuffering,
+    pub room_users: RoomUserMap,
+    pub room_presence: RoomPresence,
+    pub dedupe: RoomDedupe,
+    pub event_log: RoomEventLog,
+    pub sessions: RoomSessions,
+    pub pubsub: Option<RoomPubSub>,
+    pub config: Arc<Config>,
 }
@@ -1,33 +1,59 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use actix_cors::Cors;
 use actix_web::{App, HttpServer, web};
 use anyhow::Error;
 use lofi_party::{
-    AppState, RoomSync, RoomUserMap,
+    AppState, RoomDedupe, RoomEventLog, RoomSessions, RoomSync, RoomUserMap,
     db::db::{connect_to_db},
     services::{
-        room::create_new_room,
+        connection_limit::ConnectionLimiter,
+        dedupe::{MemoryDedupeStore, RedisDedupeStore},
+        event_log::{MemoryEventLog, RedisEventLog},
+        message::get_message_history,
+        moderation::{ban_member, kick_member},
+        presence::run_presence_sweeper,
+        pubsub::RoomPubSub,
+        reaction::get_reaction_timeline,
+        reaper::run_reaper,
+        room::{create_new_room, get_room_details},
+        session::{MemorySessionStore, RedisSessionStore},
+        sync_store::{MemorySyncStore, RedisSyncStore},
         user::create_new_user,
     },
     ws_conn::{self, handle_connection},
 };
 
-use mongodb::Database;
 use tokio::{
     signal::{self},
     sync::RwLock,
 };
 
-async fn run_websocket(db: Database, room_users_collection: RoomUserMap, room_sync: RoomSync) {
+async fn run_websocket(app_state: AppState) {
     let server = ws_conn::create_websocket_connection().await.unwrap();
 
+    let limiter = ConnectionLimiter::new(
+        app_state.config.max_connections,
+        app_state.config.max_connections_per_ip,
+    );
+
     while let Ok((stream, addr)) = server.accept().await {
-        handle_connection(stream, addr, db.clone(), room_sync.clone(), room_users_collection.clone()).await;
+        // Dropping the stream refuses the client before the WebSocket handshake
+        let Some(permit) = limiter.try_acquire(addr.ip()) else {
+            log::warn!("Connection limit reached, refusing: {:?}", addr);
+            continue;
+        };
+
+        let app_state = app_state.clone();
+
+        tokio::spawn(async move {
+            handle_connection(stream, addr, app_state).await;
+            drop(permit);
+        });
     }
 }
 
-async fn run_api(db: Database, http_port: String, room_sync: RoomSync) -> Result<(), Error> {
+async fn run_api(http_port: String, app_state: AppState) -> Result<(), Error> {
     HttpServer::new(move || {
         App::new()
             .wrap(
@@ -36,12 +62,14 @@ async fn run_api(db: Database, http_port: String, room_sync: RoomSync) -> Result
                     .allow_any_header()
                     .allow_any_method(),
             )
-            .app_data(web::Data::new(AppState {
-                db: db.clone(),
-                room_sync: room_sync.clone(),
-            }))
+            .app_data(web::Data::new(app_state.clone()))
             .service(create_new_user)
             .service(create_new_room)
+            .service(get_room_details)
+            .service(get_message_history)
+            .service(get_reaction_timeline)
+            .service(kick_member)
+            .service(ban_member)
     })
     .bind(("localhost", http_port.parse::<u16>().unwrap()))
     .unwrap()
@@ -57,23 +85,93 @@ async fn main() -> Result<(), Error> {
     dotenv::dotenv().ok();
     tracing_subscriber::fmt::init();
 
-    let config = lofi_party::config::Config::get_config();
+    let config = Arc::new(lofi_party::config::Config::get_config());
 
-    let (db, _, _) = connect_to_db(config.mongodb_url).await?;
-
-    // Spawn Actix HTTP server
-    let http_db_clone = db.clone();
+    let (db, _, _) = connect_to_db(config.mongodb_url.clone()).await?;
 
     let users_connection: RoomUserMap = Arc::new(RwLock::new(HashMap::new()));
-    let room_sync: RoomSync = Arc::new(RwLock::new(HashMap::new()));
+    let room_sync: RoomSync =
+        match RedisSyncStore::connect(&config.redis_url, config.sync_ttl_secs).await {
+            Ok(store) => Arc::new(store),
+            Err(e) => {
+                log::warn!("Redis unavailable, keeping sync state in memory: {}", e);
+                Arc::new(MemorySyncStore::default())
+            }
+        };
+
+    let dedupe: RoomDedupe =
+        match RedisDedupeStore::connect(&config.redis_url, config.dedupe_window_secs).await {
+            Ok(store) => Arc::new(store),
+            Err(e) => {
+                log::warn!("Redis unavailable, keeping dedupe window in memory: {}", e);
+                Arc::new(MemoryDedupeStore::new(config.dedupe_window_secs))
+            }
+        };
+
+    let event_log: RoomEventLog = match RedisEventLog::connect(
+        &config.redis_url,
+        config.event_log_size,
+        config.sync_ttl_secs,
+    )
+    .await
+    {
+        Ok(store) => Arc::new(store),
+        Err(e) => {
+            log::warn!("Redis unavailable, keeping the event log in memory: {}", e);
+            Arc::new(MemoryEventLog::new(config.event_log_size))
+        }
+    };
+
+    let sessions: RoomSessions =
+        match RedisSessionStore::connect(&config.redis_url, config.sync_ttl_secs).await {
+            Ok(store) => Arc::new(store),
+            Err(e) => {
+                log::warn!("Redis unavailable, keeping sessions in memory: {}", e);
+                Arc::new(MemorySessionStore::default())
+            }
+        };
+
+    // Without Redis we still serve rooms, but only sockets on this instance
+    let pubsub = match RoomPubSub::connect(&config.redis_url).await {
+        Ok(pubsub) => {
+            tokio::spawn(pubsub.clone().run_subscriber(users_connection.clone()));
+            Some(pubsub)
+        }
+        Err(e) => {
+            log::warn!("Redis unavailable, broadcasting to local sockets only: {}", e);
+            None
+        }
+    };
+
+    let app_state = AppState {
+        db,
+        room_sync,
+        room_buffering: Arc::new(RwLock::new(HashMap::new())),
+        room_users: users_connection,
+        room_presence: Arc::new(RwLock::new(HashMap::new())),
+        dedupe,
+        event_log,
+        sessions,
+        pubsub,
+        config: config.clone(),
+    };
+
+    tokio::spawn(run_presence_sweeper(app_state.clone()));
+
+    tokio::spawn(run_reaper(
+        app_state.clone(),
+        Duration::from_secs(config.reaper_interval_secs),
+        Duration::from_secs(config.room_idle_timeout_secs),
+        config.archive_idle_rooms,
+    ));
 
     tokio::select! {
-        result = run_api(http_db_clone, config.http_port, room_sync.clone()) => {
+        result = run_api(config.http_port.clone(), app_state.clone()) => {
             if let Err(e) = result {
                 log::error!("API server error: {}", e);
             }
         }
-        _ = run_websocket(db, users_connection, room_sync) => {}
+        _ = run_websocket(app_state) => {}
         _ = signal::ctrl_c() => {
             log::info!("Shutdown singal received. Stopping...");
         }
@@ -0,0 +1,9 @@
+// Same smoothing factor TCP uses for its round trip estimate
+const RTT_SMOOTHING: f64 = 0.125;
+
+pub fn smooth_rtt(previous: Option<f64>, sample_ms: f64) -> f64 {
+    match previous {
+        Some(previous) => previous + RTT_SMOOTHING * (sample_ms - previous),
+        None => sample_ms,
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    global: Arc<Semaphore>,
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    max_per_ip: usize,
+}
+
+// Held for the lifetime of a connection, frees both slots when dropped
+pub struct ConnectionPermit {
+    _global: OwnedSemaphorePermit,
+    ip: IpAddr,
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: usize, max_per_ip: usize) -> Self {
+        Self {
+            global: Arc::new(Semaphore::new(max_connections)),
+            per_ip: Arc::new(Mutex::new(HashMap::new())),
+            max_per_ip,
+        }
+    }
+
+    // Returns None when either the server or this address is at its limit
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<ConnectionPermit> {
+        let global = self.global.clone().try_acquire_owned().ok()?;
+
+        let mut per_ip = self.per_ip.lock().unwrap();
+        let count = per_ip.entry(ip).or_default();
+
+        if *count >= self.max_per_ip {
+            if *count == 0 {
+                per_ip.remove(&ip);
+            }
+
+            return None;
+        }
+
+        *count += 1;
+
+        Some(ConnectionPermit {
+            _global: global,
+            ip,
+            per_ip: self.per_ip.clone(),
+        })
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut per_ip = self.per_ip.lock().unwrap();
+
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn refuses_an_address_at_its_cap_until_a_permit_drops() {
+        let limiter = ConnectionLimiter::new(10, 2);
+
+        let first = limiter.try_acquire(ip(1)).unwrap();
+        let _second = limiter.try_acquire(ip(1)).unwrap();
+
+        assert!(limiter.try_acquire(ip(1)).is_none());
+        assert!(limiter.try_acquire(ip(2)).is_some());
+
+        drop(first);
+
+        assert!(limiter.try_acquire(ip(1)).is_some());
+    }
+
+    #[test]
+    fn refuses_everyone_at_the_global_cap_until_a_permit_drops() {
+        let limiter = ConnectionLimiter::new(2, 5);
+
+        let first = limiter.try_acquire(ip(1)).unwrap();
+        let _second = limiter.try_acquire(ip(2)).unwrap();
+
+        assert!(limiter.try_acquire(ip(3)).is_none());
+        // A refusal at the global cap leaves no per address count behind
+        assert!(!limiter.per_ip.lock().unwrap().contains_key(&ip(3)));
+
+        drop(first);
+
+        assert!(limiter.try_acquire(ip(3)).is_some());
+    }
+
+    #[test]
+    fn forgets_an_address_once_its_last_permit_drops() {
+        let limiter = ConnectionLimiter::new(10, 2);
+
+        let permit = limiter.try_acquire(ip(1)).unwrap();
+        drop(permit);
+
+        assert!(limiter.per_ip.lock().unwrap().is_empty());
+    }
+}
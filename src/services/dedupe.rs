@@ -0,0 +1,136 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use redis::{AsyncCommands, aio::ConnectionManager};
+use tokio::sync::RwLock;
+
+use crate::ws_conn::AckResult;
+
+const DEDUPE_KEY_PREFIX: &str = "dedupe:";
+
+// Remembers the ack of each handled client event for the length of the dedupe window
+#[async_trait]
+pub trait DedupeStore: Send + Sync {
+    async fn get(&self, key: &str) -> Option<AckResult>;
+    async fn set(&self, key: &str, result: AckResult);
+}
+
+// Request ids are only unique per client, so the user is part of the key
+pub fn dedupe_key(user_id: &str, request_id: &str) -> String {
+    format!("{}:{}", user_id, request_id)
+}
+
+pub struct MemoryDedupeStore {
+    window: Duration,
+    entries: RwLock<HashMap<String, (Instant, AckResult)>>,
+}
+
+impl MemoryDedupeStore {
+    pub fn new(window_secs: u64) -> Self {
+        Self {
+            window: Duration::from_secs(window_secs),
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl DedupeStore for MemoryDedupeStore {
+    async fn get(&self, key: &str) -> Option<AckResult> {
+        self.entries
+            .read()
+            .await
+            .get(key)
+            .filter(|(stored_at, _)| stored_at.elapsed() < self.window)
+            .map(|(_, result)| result.clone())
+    }
+
+    async fn set(&self, key: &str, result: AckResult) {
+        let mut write_entries = self.entries.write().await;
+
+        write_entries.retain(|_, (stored_at, _)| stored_at.elapsed() < self.window);
+        write_entries.insert(key.to_string(), (Instant::now(), result));
+    }
+}
+
+// Shares the window across instances so a retry landing elsewhere is still caught
+pub struct RedisDedupeStore {
+    conn: ConnectionManager,
+    window_secs: u64,
+    fallback: MemoryDedupeStore,
+}
+
+impl RedisDedupeStore {
+    pub async fn connect(redis_url: &str, window_secs: u64) -> Result<Self, anyhow::Error> {
+        let client = redis::Client::open(redis_url).map_err(|e| {
+            log::error!("Invalid Redis URL. Failed with error: {:?}", e);
+            anyhow::Error::msg("Invalid Redis URL")
+        })?;
+
+        let conn = client.get_connection_manager().await.map_err(|e| {
+            log::error!("Failed to connect to Redis. Failed with error: {:?}", e);
+            anyhow::Error::msg("Failed to connect to Redis")
+        })?;
+
+        Ok(Self {
+            conn,
+            window_secs,
+            fallback: MemoryDedupeStore::new(window_secs),
+        })
+    }
+
+    fn key(key: &str) -> String {
+        format!("{}{}", DEDUPE_KEY_PREFIX, key)
+    }
+}
+
+#[async_trait]
+impl DedupeStore for RedisDedupeStore {
+    async fn get(&self, key: &str) -> Option<AckResult> {
+        let mut conn = self.conn.clone();
+
+        match conn.get::<_, Option<String>>(Self::key(key)).await {
+            Ok(Some(value)) => match serde_json::from_str::<AckResult>(&value) {
+                Ok(result) => Some(result),
+                Err(err) => {
+                    log::error!(
+                        "Failed to parse stored ack for event: {}. Failed with error: {:?}",
+                        key,
+                        err
+                    );
+                    None
+                }
+            },
+            Ok(None) => self.fallback.get(key).await,
+            Err(err) => {
+                log::error!(
+                    "Failed to read stored ack from Redis. Failed with error: {:?}",
+                    err
+                );
+                self.fallback.get(key).await
+            }
+        }
+    }
+
+    async fn set(&self, key: &str, result: AckResult) {
+        let mut conn = self.conn.clone();
+
+        if let Err(err) = conn
+            .set_ex::<_, _, ()>(
+                Self::key(key),
+                serde_json::to_string(&result).unwrap(),
+                self.window_secs,
+            )
+            .await
+        {
+            log::error!(
+                "Failed to write stored ack to Redis. Failed with error: {:?}",
+                err
+            );
+            self.fallback.set(key, result).await;
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::{HashMap, VecDeque};
+
+use async_trait::async_trait;
+use redis::{AsyncCommands, aio::ConnectionManager};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::services::message::Delivery;
+
+const EVENTS_KEY_PREFIX: &str = "events:";
+const SEQ_KEY_PREFIX: &str = "events:seq:";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggedEvent {
+    pub seq: u64,
+    pub delivery: Delivery,
+    // The frame exactly as it went out, seq included
+    pub payload: String,
+}
+
+// Keeps the most recent room broadcasts so a reconnecting client can catch up
+#[async_trait]
+pub trait EventLog: Send + Sync {
+    // Stamps the frame with the room's next sequence number and keeps it for replay
+    async fn append(&self, room_id: &str, delivery: &Delivery, payload: &str) -> String;
+    async fn last_seq(&self, room_id: &str) -> u64;
+    // None when some of the events after last_seq were already trimmed away
+    async fn since(&self, room_id: &str, last_seq: u64) -> Option<Vec<LoggedEvent>>;
+    async fn remove(&self, room_id: &str);
+}
+
+// Adds the sequence number next to response_type and data, leaving other frames untouched
+fn stamp(payload: &str, seq: u64) -> String {
+    match serde_json::from_str::<serde_json::Value>(payload) {
+        Ok(serde_json::Value::Object(mut frame)) => {
+            frame.insert("seq".to_string(), seq.into());
+            serde_json::Value::Object(frame).to_string()
+        }
+        _ => payload.to_string(),
+    }
+}
+
+fn events_after(
+    events: impl Iterator<Item = LoggedEvent>,
+    current_seq: u64,
+    last_seq: u64,
+) -> Option<Vec<LoggedEvent>> {
+    if last_seq >= current_seq {
+        return Some(Vec::new());
+    }
+
+    let missed: Vec<LoggedEvent> = events.filter(|event| event.seq > last_seq).collect();
+
+    // Anything short of every seq up to the current one means the log no longer covers the gap
+    if missed.first().is_none_or(|event| event.seq != last_seq + 1) {
+        return None;
+    }
+
+    Some(missed)
+}
+
+#[derive(Default)]
+struct RoomLog {
+    seq: u64,
+    events: VecDeque<LoggedEvent>,
+}
+
+pub struct MemoryEventLog {
+    capacity: usize,
+    rooms: RwLock<HashMap<String, RoomLog>>,
+}
+
+impl MemoryEventLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            rooms: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl EventLog for MemoryEventLog {
+    async fn append(&self, room_id: &str, delivery: &Delivery, payload: &str) -> String {
+        let mut write_rooms = self.rooms.write().await;
+        let room_log = write_rooms.entry(room_id.to_string()).or_default();
+
+        room_log.seq += 1;
+
+        let payload = stamp(payload, room_log.seq);
+
+        room_log.events.push_back(LoggedEvent {
+            seq: room_log.seq,
+            delivery: delivery.clone(),
+            payload: payload.clone(),
+        });
+
+        while room_log.events.len() > self.capacity {
+            room_log.events.pop_front();
+        }
+
+        payload
+    }
+
+    async fn last_seq(&self, room_id: &str) -> u64 {
+        self.rooms
+            .read()
+            .await
+            .get(room_id)
+            .map_or(0, |room_log| room_log.seq)
+    }
+
+    async fn since(&self, room_id: &str, last_seq: u64) -> Option<Vec<LoggedEvent>> {
+        let read_rooms = self.rooms.read().await;
+
+        let Some(room_log) = read_rooms.get(room_id) else {
+            return (last_seq == 0).then(Vec::new);
+        };
+
+        events_after(room_log.events.iter().cloned(), room_log.seq, last_seq)
+    }
+
+    async fn remove(&self, room_id: &str) {
+        self.rooms.write().await.remove(room_id);
+    }
+}
+
+// Shares sequence numbers and the log across instances, so a client can resume on any of them
+pub struct RedisEventLog {
+    conn: ConnectionManager,
+    capacity: usize,
+    ttl_secs: u64,
+    fallback: MemoryEventLog,
+}
+
+impl RedisEventLog {
+    pub async fn connect(
+        redis_url: &str,
+        capacity: usize,
+        ttl_secs: u64,
+    ) -> Result<Self, anyhow::Error> {
+        let client = redis::Client::open(redis_url).map_err(|e| {
+            log::error!("Invalid Redis URL. Failed with error: {:?}", e);
+            anyhow::Error::msg("Invalid Redis URL")
+        })?;
+
+        let conn = client.get_connection_manager().await.map_err(|e| {
+            log::error!("Failed to connect to Redis. Failed with error: {:?}", e);
+            anyhow::Error::msg("Failed to connect to Redis")
+        })?;
+
+        Ok(Self {
+            conn,
+            capacity: capacity.max(1),
+            ttl_secs,
+            fallback: MemoryEventLog::new(capacity),
+        })
+    }
+
+    fn events_key(room_id: &str) -> String {
+        format!("{}{}", EVENTS_KEY_PREFIX, room_id)
+    }
+
+    fn seq_key(room_id: &str) -> String {
+        format!("{}{}", SEQ_KEY_PREFIX, room_id)
+    }
+
+    async fn try_append(
+        &self,
+        room_id: &str,
+        delivery: &Delivery,
+        payload: &str,
+    ) -> redis::RedisResult<String> {
+        let mut conn = self.conn.clone();
+
+        let seq: u64 = conn.incr(Self::seq_key(room_id), 1).await?;
+        let payload = stamp(payload, seq);
+
+        let event = LoggedEvent {
+            seq,
+            delivery: delivery.clone(),
+            payload: payload.clone(),
+        };
+
+        // Scored by seq, so appends racing on other instances still read back in order
+        redis::pipe()
+            .zadd(
+                Self::events_key(room_id),
+                serde_json::to_string(&event).unwrap(),
+                seq,
+            )
+            .zremrangebyrank(Self::events_key(room_id), 0, -(self.capacity as isize) - 1)
+            .expire(Self::events_key(room_id), self.ttl_secs as i64)
+            .expire(Self::seq_key(room_id), self.ttl_secs as i64)
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(payload)
+    }
+
+    async fn try_since(
+        &self,
+        room_id: &str,
+        last_seq: u64,
+    ) -> redis::RedisResult<Option<Vec<LoggedEvent>>> {
+        let mut conn = self.conn.clone();
+
+        let current_seq: Option<u64> = conn.get(Self::seq_key(room_id)).await?;
+        let entries: Vec<String> = conn.zrange(Self::events_key(room_id), 0, -1).await?;
+
+        let events = entries
+            .iter()
+            .filter_map(|entry| serde_json::from_str::<LoggedEvent>(entry).ok());
+
+        Ok(events_after(events, current_seq.unwrap_or(0), last_seq))
+    }
+}
+
+#[async_trait]
+impl EventLog for RedisEventLog {
+    async fn append(&self, room_id: &str, delivery: &Delivery, payload: &str) -> String {
+        match self.try_append(room_id, delivery, payload).await {
+            Ok(payload) => payload,
+            Err(err) => {
+                log::error!(
+                    "Failed to append to the event log in Redis. Failed with error: {:?}",
+                    err
+                );
+                self.fallback.append(room_id, delivery, payload).await
+            }
+        }
+    }
+
+    async fn last_seq(&self, room_id: &str) -> u64 {
+        let mut conn = self.conn.clone();
+
+        match conn.get::<_, Option<u64>>(Self::seq_key(room_id)).await {
+            Ok(seq) => seq.unwrap_or(0),
+            Err(err) => {
+                log::error!(
+                    "Failed to read the event sequence from Redis. Failed with error: {:?}",
+                    err
+                );
+                self.fallback.last_seq(room_id).await
+            }
+        }
+    }
+
+    async fn since(&self, room_id: &str, last_seq: u64) -> Option<Vec<LoggedEvent>> {
+        match self.try_since(room_id, last_seq).await {
+            Ok(events) => events,
+            Err(err) => {
+                log::error!(
+                    "Failed to read the event log from Redis. Failed with error: {:?}",
+                    err
+                );
+                self.fallback.since(room_id, last_seq).await
+            }
+        }
+    }
+
+    async fn remove(&self, room_id: &str) {
+        let mut conn = self.conn.clone();
+
+        if let Err(err) = conn
+            .del::<_, ()>(&[Self::events_key(room_id), Self::seq_key(room_id)])
+            .await
+        {
+            log::error!(
+                "Failed to delete the event log from Redis. Failed with error: {:?}",
+                err
+            );
+        }
+
+        self.fallback.remove(room_id).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged(seqs: &[u64]) -> Vec<LoggedEvent> {
+        seqs.iter()
+            .map(|seq| LoggedEvent {
+                seq: *seq,
+                delivery: Delivery::Everyone,
+                payload: seq.to_string(),
+            })
+            .collect()
+    }
+
+    fn seqs(events: Option<Vec<LoggedEvent>>) -> Option<Vec<u64>> {
+        events.map(|events| events.iter().map(|event| event.seq).collect())
+    }
+
+    #[test]
+    fn stamps_json_frames_with_their_seq() {
+        let stamped: serde_json::Value =
+            serde_json::from_str(&stamp(r#"{"response_type":"Message","data":{}}"#, 7)).unwrap();
+
+        assert_eq!(stamped["seq"], 7);
+        assert_eq!(stamped["response_type"], "Message");
+    }
+
+    #[test]
+    fn leaves_other_frames_untouched() {
+        assert_eq!(stamp("not json", 3), "not json");
+        assert_eq!(stamp("[1,2]", 3), "[1,2]");
+    }
+
+    #[test]
+    fn replays_everything_after_last_seq() {
+        let missed = events_after(logged(&[3, 4, 5, 6]).into_iter(), 6, 4);
+
+        assert_eq!(seqs(missed), Some(vec![5, 6]));
+    }
+
+    #[test]
+    fn nothing_to_replay_when_caught_up() {
+        assert_eq!(
+            seqs(events_after(logged(&[3, 4]).into_iter(), 4, 4)),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn last_seq_ahead_of_the_log_replays_nothing() {
+        assert_eq!(
+            seqs(events_after(logged(&[3, 4]).into_iter(), 4, 9)),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn trimmed_gap_asks_for_a_snapshot() {
+        // Seq 2 was already trimmed away
+        assert_eq!(seqs(events_after(logged(&[3, 4]).into_iter(), 4, 1)), None);
+        assert_eq!(seqs(events_after(Vec::new().into_iter(), 4, 1)), None);
+    }
+
+    #[tokio::test]
+    async fn memory_log_trims_to_capacity() {
+        let event_log = MemoryEventLog::new(2);
+
+        for _ in 0..3 {
+            event_log.append("room", &Delivery::Everyone, "{}").await;
+        }
+
+        assert_eq!(event_log.last_seq("room").await, 3);
+        assert_eq!(seqs(event_log.since("room", 1).await), Some(vec![2, 3]));
+        assert_eq!(seqs(event_log.since("room", 0).await), None);
+    }
+
+    #[tokio::test]
+    async fn unknown_room_only_covers_a_fresh_client() {
+        let event_log = MemoryEventLog::new(2);
+
+        assert_eq!(seqs(event_log.since("room", 0).await), Some(vec![]));
+        assert_eq!(seqs(event_log.since("room", 5).await), None);
+    }
+}
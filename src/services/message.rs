@@ -1,96 +1,634 @@
-use futures_util::{SinkExt};
+use std::collections::HashMap;
+
+use actix_web::{HttpResponse, get, web};
+use futures_util::TryStreamExt;
 use mongodb::{
     Database,
-    bson::{doc, to_bson},
+    bson::{DateTime, doc, oid::ObjectId},
+    options::ReturnDocument,
 };
 use serde::{Deserialize, Serialize};
-use tokio_tungstenite::{tungstenite::Message as TokioMessage};
+use tokio_tungstenite::tungstenite::Message as TokioMessage;
 
-use crate::{
-    db::db::{Message, Room, User},
-};
+use crate::actions::roles::get_room;
+use crate::db::db::{Message, Role, Room, User};
+use crate::services::outbound::FrameKind;
+use crate::services::video::{lead_sync_frame, now_millis};
 
-use crate::RoomUserMap;
+use crate::{AppState, RoomUserMap};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AddMessageResponse {
+    pub id: String,
     pub user_id: String,
     pub username: String,
     pub name: String,
     pub avatar: String,
     pub message: String,
+    // Milliseconds since the UNIX epoch
+    pub created_at: i64,
+    pub edited_at: Option<i64>,
+    pub deleted: bool,
+    pub reactions: Vec<ReactionSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionSummary {
+    pub emoji: String,
+    pub count: usize,
+    pub users: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionUpdate {
+    pub message_id: String,
+    pub reactions: Vec<ReactionSummary>,
+}
+
+// Emoji are stored as document keys, which must stay short and free of `.`/`$`
+pub const MAX_EMOJI_LEN: usize = 32;
+
+const DEFAULT_HISTORY_LIMIT: u32 = 50;
+pub const MAX_HISTORY_LIMIT: u32 = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageHistoryResponse {
+    pub messages: Vec<AddMessageResponse>,
+    // Pass back as `before` to fetch the next older page
+    pub next_before: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageHistoryQuery {
+    before: Option<String>,
+    limit: Option<u32>,
+}
+
+fn summarize_reactions(reactions: &HashMap<String, Vec<ObjectId>>) -> Vec<ReactionSummary> {
+    let mut summaries: Vec<ReactionSummary> = reactions
+        .iter()
+        .filter(|(_, users)| !users.is_empty())
+        .map(|(emoji, users)| ReactionSummary {
+            emoji: emoji.clone(),
+            count: users.len(),
+            users: users.iter().map(|user| user.to_hex()).collect(),
+        })
+        .collect();
+
+    summaries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.emoji.cmp(&b.emoji)));
+    summaries
+}
+
+fn to_message_response(message: Message, author: Option<&User>) -> AddMessageResponse {
+    AddMessageResponse {
+        reactions: summarize_reactions(&message.reactions),
+        id: message.id.map(|id| id.to_hex()).unwrap_or_default(),
+        user_id: message.user_id.to_string(),
+        username: author.map(|user| user.username.clone()).unwrap_or_default(),
+        name: author.map(|user| user.name.clone()).unwrap_or_default(),
+        avatar: author.map(|user| user.avatar.clone()).unwrap_or_default(),
+        message: message.message,
+        created_at: message.created_at.timestamp_millis(),
+        edited_at: message
+            .edited_at
+            .map(|edited_at| edited_at.timestamp_millis()),
+        deleted: message.deleted_at.is_some(),
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MessageError {
+    #[error("Invalid message id")]
+    InvalidId,
+    #[error("Message not found")]
+    NotFound,
+    #[error("Only the author can edit this message, room moderators may only delete it")]
+    Forbidden,
+    #[error("Invalid reaction")]
+    InvalidReaction,
+    #[error("Failed to update the message")]
+    Internal,
+}
+
+impl MessageError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            MessageError::InvalidId => "invalid_message_id",
+            MessageError::NotFound => "message_not_found",
+            MessageError::Forbidden => "forbidden",
+            MessageError::InvalidReaction => "invalid_reaction",
+            MessageError::Internal => "internal_error",
+        }
+    }
+}
+
+// Loads a live message of the room the actor is allowed to change, as its author or, when
+// `allow_moderators` is set, as a room moderator
+async fn find_editable_message(
+    db: &Database,
+    room_id: &str,
+    message_id: &str,
+    actor: ObjectId,
+    allow_moderators: bool,
+) -> Result<Message, MessageError> {
+    let message_collection = db.collection::<Message>("messages");
+
+    let message_id = ObjectId::parse_str(message_id).map_err(|_| MessageError::InvalidId)?;
+
+    let message = match message_collection
+        .find_one(doc! {"_id": message_id, "room_id": room_id, "deleted_at": null})
+        .await
+    {
+        Ok(Some(message)) => message,
+        Ok(None) => return Err(MessageError::NotFound),
+        Err(err) => {
+            log::error!("Failed to fetch message. Failed with error: {:?}", err);
+
+            return Err(MessageError::Internal);
+        }
+    };
+
+    if message.user_id == actor {
+        return Ok(message);
+    }
+
+    if !allow_moderators {
+        return Err(MessageError::Forbidden);
+    }
+
+    match get_room(message.room_id.clone(), db.clone()).await {
+        Ok(room) if room.role_of(&actor) != Role::Viewer => Ok(message),
+        Ok(_) => Err(MessageError::Forbidden),
+        Err(_) => Err(MessageError::NotFound),
+    }
+}
+
+async fn update_message(
+    db: &Database,
+    message_id: ObjectId,
+    update: mongodb::bson::Document,
+) -> Result<AddMessageResponse, MessageError> {
+    let message_collection = db.collection::<Message>("messages");
+    let user_collection = db.collection::<User>("users");
+
+    let message = match message_collection
+        .find_one_and_update(doc! {"_id": message_id, "deleted_at": null}, update)
+        .return_document(ReturnDocument::After)
+        .await
+    {
+        Ok(Some(message)) => message,
+        Ok(None) => return Err(MessageError::NotFound),
+        Err(err) => {
+            log::error!("Failed to update message. Failed with error: {:?}", err);
+
+            return Err(MessageError::Internal);
+        }
+    };
+
+    let author = user_collection
+        .find_one(doc! {"_id": message.user_id})
+        .await
+        .unwrap_or_default();
+
+    Ok(to_message_response(message, author.as_ref()))
+}
+
+// Only the author may edit, messages of other rooms are reported as not found
+pub async fn edit_message(
+    db: Database,
+    room_id: &str,
+    message_id: &str,
+    actor: ObjectId,
+    text: String,
+) -> Result<AddMessageResponse, MessageError> {
+    let message = find_editable_message(&db, room_id, message_id, actor, false).await?;
+
+    update_message(
+        &db,
+        message.id.ok_or(MessageError::NotFound)?,
+        doc! {"$set": {"message": text, "edited_at": DateTime::now()}},
+    )
+    .await
+}
+
+// Soft deletes by clearing the text and leaving a tombstone in place, moderators may delete too
+pub async fn delete_message(
+    db: Database,
+    room_id: &str,
+    message_id: &str,
+    actor: ObjectId,
+) -> Result<AddMessageResponse, MessageError> {
+    let message = find_editable_message(&db, room_id, message_id, actor, true).await?;
+
+    update_message(
+        &db,
+        message.id.ok_or(MessageError::NotFound)?,
+        doc! {"$set": {"message": "", "deleted_at": DateTime::now()}},
+    )
+    .await
 }
 
 pub async fn add_message(
     db: Database,
-    room_id: String,
     message: Message,
 ) -> Result<AddMessageResponse, anyhow::Error> {
+    let message_collection = db.collection::<Message>("messages");
+    let user_collection = db.collection::<User>("users");
+
+    match message_collection.insert_one(message.clone()).await {
+        Ok(_) => match user_collection
+            .find_one(doc! {"_id": message.user_id})
+            .await
+        {
+            Ok(result) => {
+                if let Some(user) = result {
+                    Ok(to_message_response(message, Some(&user)))
+                } else {
+                    Err(anyhow::Error::msg("Failed to fetch user info"))
+                }
+            }
+            Err(err) => {
+                log::error!("Failed to fetch user info. Failed with error: {:?}", err);
+
+                Err(anyhow::Error::msg("Failed to fetch user info"))
+            }
+        },
+        Err(err) => {
+            log::error!(
+                "Failed to insert message into message collection. Failed with error: {:?}",
+                err
+            );
+
+            Err(anyhow::Error::msg(
+                "Failed to insert message into message collection",
+            ))
+        }
+    }
+}
+
+// Adds or removes the actor's reaction on a message of the room, returning the message's new totals
+pub async fn set_reaction(
+    db: Database,
+    room_id: &str,
+    message_id: &str,
+    actor: ObjectId,
+    emoji: &str,
+    add: bool,
+) -> Result<ReactionUpdate, MessageError> {
+    let message_collection = db.collection::<Message>("messages");
+
+    if emoji.is_empty()
+        || emoji.len() > MAX_EMOJI_LEN
+        || emoji.contains('.')
+        || emoji.starts_with('$')
+    {
+        return Err(MessageError::InvalidReaction);
+    }
+
+    let message_id = ObjectId::parse_str(message_id).map_err(|_| MessageError::InvalidId)?;
+    let field = format!("reactions.{}", emoji);
+
+    let update = if add {
+        doc! {"$addToSet": {field.clone(): actor}}
+    } else {
+        doc! {"$pull": {field.clone(): actor}}
+    };
+
+    let message = match message_collection
+        .find_one_and_update(
+            doc! {"_id": message_id, "room_id": room_id, "deleted_at": null},
+            update,
+        )
+        .return_document(ReturnDocument::After)
+        .await
+    {
+        Ok(Some(message)) => message,
+        Ok(None) => return Err(MessageError::NotFound),
+        Err(err) => {
+            log::error!("Failed to update reaction. Failed with error: {:?}", err);
+
+            return Err(MessageError::Internal);
+        }
+    };
+
+    // Drop emoji nobody reacts with anymore so documents don't collect empty keys
+    let emptied = message
+        .reactions
+        .get(emoji)
+        .is_some_and(|users| users.is_empty());
+
+    if !add
+        && emptied
+        && let Err(err) = message_collection
+            .update_one(
+                doc! {"_id": message_id, field.clone(): {"$size": 0}},
+                doc! {"$unset": {field: ""}},
+            )
+            .await
+    {
+        log::error!("Failed to clean up reaction. Failed with error: {:?}", err);
+    }
+
+    Ok(ReactionUpdate {
+        message_id: message_id.to_hex(),
+        reactions: summarize_reactions(&message.reactions),
+    })
+}
+
+// Returns `None` when the room does not exist, newest page first when `before` is unset
+pub async fn get_messages(
+    db: Database,
+    room_id: String,
+    before: Option<ObjectId>,
+    limit: u32,
+) -> Result<Option<MessageHistoryResponse>, anyhow::Error> {
     let room_collection = db.collection::<Room>("rooms");
+    let message_collection = db.collection::<Message>("messages");
     let user_collection = db.collection::<User>("users");
 
     match room_collection
-        .update_one(
-            doc! {"room_id": room_id},
-            doc! {"$push": {"messages": to_bson(&message.clone()).unwrap() }},
-        )
+        .count_documents(doc! {"room_id": room_id.clone()})
         .await
     {
-        Ok(_) => {
-            match user_collection
-                .find_one(doc! {"_id": message.user_id})
-                .await
-            {
-                Ok(result) => {
-                    if let Some(user) = result {
-                        Ok(AddMessageResponse {
-                            user_id: message.user_id.to_string(),
-                            username: user.username,
-                            name: user.name,
-                            avatar: user.avatar,
-                            message: message.message,
-                        })
-                    } else {
-                        Err(anyhow::Error::msg("Failed to fetch user info"))
-                    }
-                }
-                Err(err) => {
-                    log::error!("Failed to fetch user info. Failed with error: {:?}", err);
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        Err(err) => {
+            log::error!("Failed to fetch room. Failed with error: {:?}", err);
 
-                    Err(anyhow::Error::msg("Failed to fetch user info"))
-                }
-            }
+            return Err(anyhow::Error::msg("Failed to fetch room"));
         }
+    }
+
+    let mut filter = doc! {"room_id": room_id.clone()};
+
+    if let Some(before) = before {
+        let anchor = match message_collection.find_one(doc! {"_id": before}).await {
+            Ok(Some(anchor)) => anchor,
+            Ok(None) => {
+                return Ok(Some(MessageHistoryResponse {
+                    messages: Vec::new(),
+                    next_before: None,
+                }));
+            }
+            Err(err) => {
+                log::error!(
+                    "Failed to fetch message cursor. Failed with error: {:?}",
+                    err
+                );
+
+                return Err(anyhow::Error::msg("Failed to fetch message cursor"));
+            }
+        };
+
+        filter.insert(
+            "$or",
+            vec![
+                doc! {"created_at": {"$lt": anchor.created_at}},
+                doc! {"created_at": anchor.created_at, "_id": {"$lt": before}},
+            ],
+        );
+    }
+
+    // One extra row tells us whether an older page exists
+    let mut messages: Vec<Message> = match message_collection
+        .find(filter)
+        .sort(doc! {"created_at": -1, "_id": -1})
+        .limit(limit as i64 + 1)
+        .await
+    {
+        Ok(cursor) => cursor.try_collect().await.unwrap_or_else(|err| {
+            log::error!("Failed to read room messages. Failed with error: {:?}", err);
+            Vec::new()
+        }),
         Err(err) => {
             log::error!(
-                "Failed to insert message into room collection. Failed with error: {:?}",
+                "Failed to fetch room messages. Failed with error: {:?}",
                 err
             );
 
-            Err(anyhow::Error::msg(
-                "Failed to insert message into room collection",
-            ))
+            return Err(anyhow::Error::msg("Failed to fetch room messages"));
         }
+    };
+
+    let has_more = messages.len() > limit as usize;
+    messages.truncate(limit as usize);
+    messages.reverse();
+
+    let next_before = if has_more {
+        messages
+            .first()
+            .and_then(|message| message.id)
+            .map(|id| id.to_hex())
+    } else {
+        None
+    };
+
+    let author_ids: Vec<ObjectId> = messages.iter().map(|message| message.user_id).collect();
+
+    let authors: HashMap<ObjectId, User> = match user_collection
+        .find(doc! {"_id": {"$in": author_ids}})
+        .await
+    {
+        Ok(cursor) => cursor
+            .try_collect::<Vec<User>>()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|user| user.id.map(|id| (id, user)))
+            .collect(),
+        Err(err) => {
+            log::error!("Failed to fetch user info. Failed with error: {:?}", err);
+
+            return Err(anyhow::Error::msg("Failed to fetch user info"));
+        }
+    };
+
+    let messages = messages
+        .into_iter()
+        .map(|message| {
+            let author = authors.get(&message.user_id);
+            to_message_response(message, author)
+        })
+        .collect();
+
+    Ok(Some(MessageHistoryResponse {
+        messages,
+        next_before,
+    }))
+}
+
+#[get("/room/{room_id}/messages")]
+pub async fn get_message_history(
+    path: web::Path<String>,
+    query: web::Query<MessageHistoryQuery>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+
+    let before = match query.before.as_deref().map(ObjectId::parse_str).transpose() {
+        Ok(before) => before,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid message cursor"),
+    };
+
+    match get_messages(app_state.db.clone(), path.into_inner(), before, limit).await {
+        Ok(Some(history)) => HttpResponse::Ok().json(history),
+        Ok(None) => HttpResponse::NotFound().body("Room not found"),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+// Who in the room a broadcast should reach
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum Delivery {
+    #[default]
+    Everyone,
+    Except(String),
+    User(String),
+    Users(Vec<String>),
+    // Members holding this role or a higher one, resolved to user ids before publishing
+    Role(Role),
+}
+
+impl Delivery {
+    pub fn includes(&self, user_id: &str) -> bool {
+        match self {
+            Delivery::Everyone => true,
+            Delivery::Except(excluded) => excluded != user_id,
+            Delivery::User(target) => target == user_id,
+            Delivery::Users(targets) => targets.iter().any(|target| target == user_id),
+            Delivery::Role(_) => false,
+        }
+    }
+}
+
+pub async fn broadcast_message(app_state: &AppState, room_id: String, message: TokioMessage) {
+    broadcast_message_to(app_state, room_id, message, Delivery::Everyone).await;
+}
+
+// Playback updates may be coalesced for clients that fall behind
+pub async fn broadcast_sync_update(app_state: &AppState, room_id: String, message: TokioMessage) {
+    broadcast_frame(
+        app_state,
+        room_id,
+        message,
+        Delivery::Everyone,
+        FrameKind::Sync,
+    )
+    .await;
+}
+
+pub async fn broadcast_message_to(
+    app_state: &AppState,
+    room_id: String,
+    message: TokioMessage,
+    delivery: Delivery,
+) {
+    broadcast_frame(app_state, room_id, message, delivery, FrameKind::Event).await;
+}
+
+// Skips the event log, so the frame is lost to anyone offline when it went out
+pub async fn broadcast_ephemeral_to(
+    app_state: &AppState,
+    room_id: String,
+    message: TokioMessage,
+    delivery: Delivery,
+) {
+    broadcast_frame(app_state, room_id, message, delivery, FrameKind::Ephemeral).await;
+}
+
+async fn broadcast_frame(
+    app_state: &AppState,
+    room_id: String,
+    message: TokioMessage,
+    delivery: Delivery,
+    kind: FrameKind,
+) {
+    let delivery = match delivery {
+        Delivery::Role(role) => match resolve_role(app_state, &room_id, role).await {
+            Some(delivery) => delivery,
+            None => return,
+        },
+        delivery => delivery,
+    };
+
+    // Logged frames get the room's next seq so reconnecting clients can ask for what they missed
+    let message = match message {
+        TokioMessage::Text(text) if kind != FrameKind::Ephemeral => TokioMessage::Text(
+            app_state
+                .event_log
+                .append(&room_id, &delivery, text.as_str())
+                .await
+                .into(),
+        ),
+        message => message,
+    };
+
+    if let (Some(pubsub), TokioMessage::Text(text)) = (&app_state.pubsub, &message) {
+        pubsub
+            .publish(&room_id, text.as_str(), &delivery, kind)
+            .await;
     }
+
+    deliver_message(
+        app_state.room_users.clone(),
+        room_id,
+        message,
+        &delivery,
+        kind,
+    )
+    .await;
 }
 
-pub async fn broadcast_message(
+async fn resolve_role(app_state: &AppState, room_id: &str, role: Role) -> Option<Delivery> {
+    let room = get_room(room_id.to_string(), app_state.db.clone())
+        .await
+        .ok()?;
+
+    let users = match role {
+        Role::Viewer => return Some(Delivery::Everyone),
+        Role::CoHost => room
+            .host
+            .iter()
+            .chain(room.co_hosts.iter())
+            .collect::<Vec<_>>(),
+        Role::Host => room.host.iter().collect::<Vec<_>>(),
+    };
+
+    Some(Delivery::Users(
+        users.into_iter().map(|user| user.to_hex()).collect(),
+    ))
+}
+
+pub async fn deliver_message(
     room_users_collection: RoomUserMap,
     room_id: String,
     message: TokioMessage,
+    delivery: &Delivery,
+    kind: FrameKind,
 ) {
     let read = room_users_collection.read().await;
 
+    // Only enqueues, so a slow client cannot hold up the rest of the room or the lock
     if let Some(users) = read.get(&room_id) {
-        for (user_id, tx) in users {
-            let mut write_tx = tx.write().await;
+        for (user_id, client) in users {
+            if !delivery.includes(user_id) {
+                continue;
+            }
 
-            if let Err(error) = write_tx.send(message.clone()).await {
-                log::error!(
-                    "Failed to send message to user: {:}. Failed with error: {:?}",
-                    user_id,
-                    error
-                )
+            // Playback updates are led by the client's one way delay so they land on the live position
+            let message = match (kind, client.rtt(), &message) {
+                (FrameKind::Sync, Some(rtt), TokioMessage::Text(text)) => {
+                    lead_sync_frame(text.as_str(), now_millis() + rtt / 2.0)
+                        .map_or_else(|| message.clone(), |led| TokioMessage::Text(led.into()))
+                }
+                _ => message.clone(),
+            };
+
+            if !client.send(message, kind) {
+                log::warn!("Failed to queue message for user: {:}", user_id);
             }
         }
     }
@@ -1,4 +1,16 @@
+pub mod clock;
+pub mod connection_limit;
+pub mod dedupe;
+pub mod event_log;
 pub mod message;
+pub mod moderation;
+pub mod outbound;
+pub mod presence;
+pub mod pubsub;
+pub mod reaction;
+pub mod reaper;
 pub mod room;
+pub mod session;
+pub mod sync_store;
 pub mod user;
 pub mod video;
@@ -0,0 +1,219 @@
+use actix_web::{HttpRequest, HttpResponse, post, web};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::Message as TokioMessage;
+
+use crate::{
+    AppState, RoomUserMap,
+    actions::{ban_user::ban_user, kick_user::kick_user, roles::get_room},
+    auth::user_from_authorization,
+    services::{message::broadcast_message, outbound::FrameKind, pubsub::RoomControl},
+    ws_conn::{WebsocketResponse, WebsocketResponseType},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModerationAction {
+    Kick,
+    Ban,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationData {
+    pub room_id: String,
+    pub user_id: String,
+    pub by: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModerationRequest {
+    user_id: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ModerationError {
+    #[error("Invalid user id")]
+    InvalidUserId,
+    #[error("Room not found")]
+    RoomNotFound,
+    #[error("Only a higher ranked member can remove this user")]
+    Forbidden,
+    #[error("Failed to update the room")]
+    Internal,
+}
+
+impl ModerationError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ModerationError::InvalidUserId => "invalid_user_id",
+            ModerationError::RoomNotFound => "room_not_found",
+            ModerationError::Forbidden => "forbidden",
+            ModerationError::Internal => "internal_error",
+        }
+    }
+}
+
+// Removes the member from the room, closes their socket wherever it is connected and tells the rest of the room
+pub async fn moderate_member(
+    app_state: &AppState,
+    room_id: String,
+    actor_id: &str,
+    target_id: &str,
+    action: ModerationAction,
+) -> Result<(), ModerationError> {
+    let actor = ObjectId::parse_str(actor_id).map_err(|_| ModerationError::InvalidUserId)?;
+    let target = ObjectId::parse_str(target_id).map_err(|_| ModerationError::InvalidUserId)?;
+
+    let room = get_room(room_id.clone(), app_state.db.clone())
+        .await
+        .map_err(|_| ModerationError::RoomNotFound)?;
+
+    if !room.can_moderate(&actor, &target) {
+        return Err(ModerationError::Forbidden);
+    }
+
+    let result = match action {
+        ModerationAction::Kick => kick_user(room_id.clone(), target, app_state.db.clone()).await,
+        ModerationAction::Ban => ban_user(room_id.clone(), target, app_state.db.clone()).await,
+    };
+
+    if result.is_err() {
+        return Err(ModerationError::Internal);
+    }
+
+    let response_type = match action {
+        ModerationAction::Kick => WebsocketResponseType::UserKicked,
+        ModerationAction::Ban => WebsocketResponseType::UserBanned,
+    };
+
+    let notice = serde_json::to_string(&WebsocketResponse {
+        response_type,
+        data: &ModerationData {
+            room_id: room_id.clone(),
+            user_id: target_id.to_string(),
+            by: actor_id.to_string(),
+        },
+    })
+    .unwrap();
+
+    // A removed member must not slip back in through a detached session
+    app_state.sessions.end_member(&room_id, target_id).await;
+
+    disconnect_member(app_state.room_users.clone(), &room_id, target_id, &notice).await;
+
+    if let Some(pubsub) = &app_state.pubsub {
+        pubsub
+            .publish_control(
+                &room_id,
+                &notice,
+                RoomControl::Disconnect {
+                    user_id: target_id.to_string(),
+                },
+            )
+            .await;
+    }
+
+    broadcast_message(app_state, room_id, TokioMessage::Text(notice.into())).await;
+
+    Ok(())
+}
+
+// Its connection loop notices the close, and skips the leave broadcast since it is no longer registered
+pub async fn disconnect_member(
+    room_users: RoomUserMap,
+    room_id: &str,
+    user_id: &str,
+    notice: &str,
+) {
+    let removed_tx = {
+        let mut write_users_connection = room_users.write().await;
+
+        let removed_tx = write_users_connection
+            .get_mut(room_id)
+            .and_then(|room_map| room_map.remove(user_id));
+
+        if write_users_connection
+            .get(room_id)
+            .is_some_and(|room_map| room_map.is_empty())
+        {
+            write_users_connection.remove(room_id);
+        }
+
+        removed_tx
+    };
+
+    if let Some(client) = removed_tx {
+        if !client.send(
+            TokioMessage::Text(notice.to_string().into()),
+            FrameKind::Event,
+        ) {
+            log::error!("Failed to notify removed user: {}", user_id);
+        }
+
+        client.close();
+    }
+}
+
+#[post("/room/{room_id}/kick")]
+pub async fn kick_member(
+    path: web::Path<String>,
+    http_req: HttpRequest,
+    req: web::Json<ModerationRequest>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    moderate_over_http(
+        path.into_inner(),
+        http_req,
+        req.into_inner(),
+        app_state,
+        ModerationAction::Kick,
+    )
+    .await
+}
+
+#[post("/room/{room_id}/ban")]
+pub async fn ban_member(
+    path: web::Path<String>,
+    http_req: HttpRequest,
+    req: web::Json<ModerationRequest>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    moderate_over_http(
+        path.into_inner(),
+        http_req,
+        req.into_inner(),
+        app_state,
+        ModerationAction::Ban,
+    )
+    .await
+}
+
+async fn moderate_over_http(
+    room_id: String,
+    http_req: HttpRequest,
+    req: ModerationRequest,
+    app_state: web::Data<AppState>,
+    action: ModerationAction,
+) -> HttpResponse {
+    let Some(actor_id) = http_req
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| user_from_authorization(value, &app_state.config.session_secret))
+    else {
+        return HttpResponse::Unauthorized().body("Invalid session token");
+    };
+
+    match moderate_member(&app_state, room_id, &actor_id, &req.user_id, action).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(err) => {
+            let message = err.to_string();
+
+            match err {
+                ModerationError::InvalidUserId => HttpResponse::BadRequest().body(message),
+                ModerationError::RoomNotFound => HttpResponse::NotFound().body(message),
+                ModerationError::Forbidden => HttpResponse::Forbidden().body(message),
+                ModerationError::Internal => HttpResponse::InternalServerError().body(message),
+            }
+        }
+    }
+}
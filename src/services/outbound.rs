@@ -0,0 +1,302 @@
+use std::{
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use futures_util::SinkExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Notify, mpsc, mpsc::error::TrySendError};
+use tokio_tungstenite::tungstenite::Message as TokioMessage;
+
+use crate::{Tx, services::clock::smooth_rtt};
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// What to do with a frame when the client's outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlowConsumerPolicy {
+    Drop,
+    // Keeps only the newest sync update, other frames are dropped
+    #[default]
+    Coalesce,
+    Disconnect,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = ();
+
+    fn from_str(input: &str) -> Result<SlowConsumerPolicy, Self::Err> {
+        match input {
+            "drop" => Ok(SlowConsumerPolicy::Drop),
+            "coalesce" => Ok(SlowConsumerPolicy::Coalesce),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum FrameKind {
+    #[default]
+    Event,
+    // Playback state, where only the latest frame matters
+    Sync,
+    // Presence, which is never logged or replayed to reconnecting clients
+    Ephemeral,
+}
+
+struct Shared {
+    coalesced: Mutex<Option<TokioMessage>>,
+    // Smoothed round trip in ms, measured by the server from heartbeat pings
+    rtt: Mutex<Option<f64>>,
+    wake: Notify,
+    stop: Notify,
+    disconnect: Notify,
+}
+
+// Cheap to clone, every clone feeds the same writer task
+#[derive(Clone)]
+pub struct ClientHandle {
+    id: u64,
+    sender: mpsc::Sender<TokioMessage>,
+    policy: SlowConsumerPolicy,
+    shared: Arc<Shared>,
+}
+
+impl ClientHandle {
+    // Takes ownership of the socket sink and drains the queue into it on its own task
+    pub fn spawn(sink: Tx, capacity: usize, policy: SlowConsumerPolicy) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+
+        let shared = Arc::new(Shared {
+            coalesced: Mutex::new(None),
+            rtt: Mutex::new(None),
+            wake: Notify::new(),
+            stop: Notify::new(),
+            disconnect: Notify::new(),
+        });
+
+        tokio::spawn(run_writer(sink, receiver, shared.clone()));
+
+        Self {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            sender,
+            policy,
+            shared,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn record_rtt_sample(&self, sample_ms: f64) -> f64 {
+        let mut rtt = self.shared.rtt.lock().unwrap();
+        let estimate = smooth_rtt(*rtt, sample_ms);
+
+        *rtt = Some(estimate);
+        estimate
+    }
+
+    pub fn rtt(&self) -> Option<f64> {
+        *self.shared.rtt.lock().unwrap()
+    }
+
+    // Never waits on the client, returns false when the frame was not queued
+    pub fn send(&self, message: TokioMessage, kind: FrameKind) -> bool {
+        if (self.policy, kind) == (SlowConsumerPolicy::Coalesce, FrameKind::Sync) {
+            return self.send_sync(message);
+        }
+
+        match self.sender.try_send(message) {
+            Ok(()) => return true,
+            Err(TrySendError::Closed(_)) => return false,
+            Err(TrySendError::Full(_)) => {}
+        }
+
+        match self.policy {
+            SlowConsumerPolicy::Disconnect => {
+                log::warn!("Outbound queue full, disconnecting connection: {}", self.id);
+                self.shared.stop.notify_one();
+                false
+            }
+            _ => {
+                log::warn!(
+                    "Outbound queue full, dropping frame for connection: {}",
+                    self.id
+                );
+                false
+            }
+        }
+    }
+
+    // Holding the slot lock keeps concurrent sync updates in the order they were sent
+    fn send_sync(&self, message: TokioMessage) -> bool {
+        let mut coalesced = self.shared.coalesced.lock().unwrap();
+
+        // Once an update waits in the slot, newer ones replace it instead of overtaking it in the queue
+        if coalesced.is_none() {
+            match self.sender.try_send(message) {
+                Ok(()) => return true,
+                Err(TrySendError::Closed(_)) => return false,
+                Err(TrySendError::Full(message)) => *coalesced = Some(message),
+            }
+        } else {
+            *coalesced = Some(message);
+        }
+
+        drop(coalesced);
+        self.shared.wake.notify_one();
+        true
+    }
+
+    // Queues a close frame, or forces the disconnect if the queue has no room for it
+    pub fn close(&self) {
+        if self.sender.try_send(TokioMessage::Close(None)).is_err() {
+            self.shared.stop.notify_one();
+        }
+    }
+
+    // Resolves once the writer has stopped or the client was dropped as a slow consumer
+    pub async fn disconnected(&self) {
+        self.shared.disconnect.notified().await;
+    }
+}
+
+async fn run_writer(mut sink: Tx, mut receiver: mpsc::Receiver<TokioMessage>, shared: Arc<Shared>) {
+    loop {
+        // Queued frames go first, none of the sync updates among them is newer than the coalesced one
+        let message = tokio::select! {
+            biased;
+            message = receiver.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = shared.wake.notified() => match shared.coalesced.lock().unwrap().take() {
+                Some(message) => message,
+                None => continue,
+            },
+            _ = shared.stop.notified() => break,
+        };
+
+        let closing = matches!(message, TokioMessage::Close(_));
+
+        // A client that stopped reading must not pin this task on a full socket
+        let result = tokio::select! {
+            result = sink.send(message) => result,
+            _ = shared.stop.notified() => break,
+        };
+
+        if let Err(error) = result {
+            log::error!(
+                "Failed to send message to the client. Failed with error: {:?}",
+                error
+            );
+            break;
+        }
+
+        if closing {
+            break;
+        }
+    }
+
+    if let Err(error) = sink.close().await {
+        log::info!("Client socket already closed: {:?}", error);
+    }
+
+    shared.disconnect.notify_one();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A handle without a writer task, the test drains the queue itself
+    fn handle(
+        capacity: usize,
+        policy: SlowConsumerPolicy,
+    ) -> (ClientHandle, mpsc::Receiver<TokioMessage>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+
+        let client = ClientHandle {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            sender,
+            policy,
+            shared: Arc::new(Shared {
+                coalesced: Mutex::new(None),
+                rtt: Mutex::new(None),
+                wake: Notify::new(),
+                stop: Notify::new(),
+                disconnect: Notify::new(),
+            }),
+        };
+
+        (client, receiver)
+    }
+
+    fn text(body: &str) -> TokioMessage {
+        TokioMessage::Text(body.into())
+    }
+
+    fn coalesced(client: &ClientHandle) -> Option<TokioMessage> {
+        client.shared.coalesced.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!("drop".parse(), Ok(SlowConsumerPolicy::Drop));
+        assert_eq!("coalesce".parse(), Ok(SlowConsumerPolicy::Coalesce));
+        assert_eq!("disconnect".parse(), Ok(SlowConsumerPolicy::Disconnect));
+        assert_eq!("block".parse::<SlowConsumerPolicy>(), Err(()));
+    }
+
+    #[test]
+    fn full_queue_keeps_only_the_newest_sync_update() {
+        let (client, _receiver) = handle(1, SlowConsumerPolicy::Coalesce);
+
+        assert!(client.send(text("s1"), FrameKind::Sync));
+        assert!(client.send(text("s2"), FrameKind::Sync));
+        assert!(client.send(text("s3"), FrameKind::Sync));
+
+        assert_eq!(coalesced(&client), Some(text("s3")));
+    }
+
+    #[test]
+    fn newer_sync_update_never_overtakes_the_coalesced_one() {
+        let (client, mut receiver) = handle(1, SlowConsumerPolicy::Coalesce);
+
+        client.send(text("s1"), FrameKind::Sync);
+        client.send(text("s2"), FrameKind::Sync);
+
+        // Room in the queue again, but s2 still waits in the slot
+        assert_eq!(receiver.try_recv().ok(), Some(text("s1")));
+        assert!(client.send(text("s3"), FrameKind::Sync));
+
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(coalesced(&client), Some(text("s3")));
+    }
+
+    #[test]
+    fn full_queue_drops_events_under_coalesce() {
+        let (client, mut receiver) = handle(1, SlowConsumerPolicy::Coalesce);
+
+        assert!(client.send(text("e1"), FrameKind::Event));
+        assert!(!client.send(text("e2"), FrameKind::Event));
+
+        assert_eq!(receiver.try_recv().ok(), Some(text("e1")));
+        assert_eq!(coalesced(&client), None);
+    }
+
+    #[test]
+    fn drop_policy_never_coalesces() {
+        let (client, _receiver) = handle(1, SlowConsumerPolicy::Drop);
+
+        assert!(client.send(text("s1"), FrameKind::Sync));
+        assert!(!client.send(text("s2"), FrameKind::Sync));
+
+        assert_eq!(coalesced(&client), None);
+    }
+}
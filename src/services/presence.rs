@@ -0,0 +1,155 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::Message as TokioMessage;
+
+use crate::{
+    AppState,
+    services::message::{Delivery, broadcast_ephemeral_to},
+    ws_conn::{WebsocketResponse, WebsocketResponseType},
+};
+
+// How late past its TTL a typing indicator may be cleared
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceData {
+    pub room_id: String,
+    pub user_id: String,
+}
+
+// Presence is never persisted, it only lives in memory and on the wire
+pub async fn start_typing(app_state: &AppState, room_id: String, user_id: String) {
+    let was_typing = {
+        let mut write_presence = app_state.room_presence.write().await;
+
+        write_presence
+            .entry(room_id.clone())
+            .or_default()
+            .insert(user_id.clone(), Instant::now())
+            .is_some()
+    };
+
+    // Repeated keepalives only push the expiry back, the sweeper does the rest
+    if !was_typing {
+        broadcast_presence(
+            app_state,
+            WebsocketResponseType::TypingStarted,
+            room_id.clone(),
+            user_id.clone(),
+        )
+        .await;
+    }
+}
+
+// A single task expires typing indicators across all rooms, instead of a timer per keepalive
+pub async fn run_presence_sweeper(app_state: AppState) {
+    let ttl = Duration::from_secs(app_state.config.typing_ttl_secs);
+    let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        let expired = {
+            let mut write_presence = app_state.room_presence.write().await;
+            let mut expired = Vec::new();
+
+            for (room_id, room_map) in write_presence.iter_mut() {
+                room_map.retain(|user_id, last_seen| {
+                    let alive = last_seen.elapsed() < ttl;
+
+                    if !alive {
+                        expired.push((room_id.clone(), user_id.clone()));
+                    }
+
+                    alive
+                });
+            }
+
+            write_presence.retain(|_, room_map| !room_map.is_empty());
+            expired
+        };
+
+        for (room_id, user_id) in expired {
+            broadcast_presence(
+                &app_state,
+                WebsocketResponseType::TypingStopped,
+                room_id,
+                user_id,
+            )
+            .await;
+        }
+    }
+}
+
+pub async fn stop_typing(app_state: &AppState, room_id: String, user_id: String) {
+    let was_typing = {
+        let mut write_presence = app_state.room_presence.write().await;
+        remove_typing(&mut write_presence, &room_id, &user_id)
+    };
+
+    if was_typing {
+        broadcast_presence(
+            app_state,
+            WebsocketResponseType::TypingStopped,
+            room_id,
+            user_id,
+        )
+        .await;
+    }
+}
+
+pub async fn set_idle(app_state: &AppState, room_id: String, user_id: String, idle: bool) {
+    let response_type = if idle {
+        WebsocketResponseType::Idle
+    } else {
+        WebsocketResponseType::Active
+    };
+
+    broadcast_presence(app_state, response_type, room_id, user_id).await;
+}
+
+fn remove_typing(
+    presence: &mut HashMap<String, HashMap<String, Instant>>,
+    room_id: &str,
+    user_id: &str,
+) -> bool {
+    let Some(room_map) = presence.get_mut(room_id) else {
+        return false;
+    };
+
+    let removed = room_map.remove(user_id).is_some();
+
+    if room_map.is_empty() {
+        presence.remove(room_id);
+    }
+
+    removed
+}
+
+async fn broadcast_presence(
+    app_state: &AppState,
+    response_type: WebsocketResponseType,
+    room_id: String,
+    user_id: String,
+) {
+    let notice = serde_json::to_string(&WebsocketResponse {
+        response_type,
+        data: &PresenceData {
+            room_id: room_id.clone(),
+            user_id: user_id.clone(),
+        },
+    })
+    .unwrap();
+
+    broadcast_ephemeral_to(
+        app_state,
+        room_id,
+        TokioMessage::Text(notice.into()),
+        Delivery::Except(user_id),
+    )
+    .await;
+}
@@ -0,0 +1,193 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use mongodb::bson::oid::ObjectId;
+use redis::{AsyncCommands, aio::ConnectionManager};
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::Message as TokioMessage;
+
+use crate::{
+    RoomUserMap,
+    services::{
+        message::{Delivery, deliver_message},
+        moderation::disconnect_member,
+        outbound::FrameKind,
+    },
+};
+
+const ROOM_CHANNEL_PREFIX: &str = "room:";
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(2);
+
+// Published on the per-room channel so every instance can fan out to its local sockets
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RoomEnvelope {
+    origin: String,
+    room_id: String,
+    payload: String,
+    #[serde(default)]
+    delivery: Delivery,
+    #[serde(default)]
+    kind: FrameKind,
+    #[serde(default)]
+    control: Option<RoomControl>,
+}
+
+// Asks whichever instance holds a socket to act on it, the payload goes to that socket only
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RoomControl {
+    Disconnect { user_id: String },
+}
+
+#[derive(Clone)]
+pub struct RoomPubSub {
+    client: redis::Client,
+    publisher: ConnectionManager,
+    instance_id: String,
+}
+
+impl RoomPubSub {
+    pub async fn connect(redis_url: &str) -> Result<Self, anyhow::Error> {
+        let client = redis::Client::open(redis_url).map_err(|e| {
+            log::error!("Invalid Redis URL. Failed with error: {:?}", e);
+            anyhow::Error::msg("Invalid Redis URL")
+        })?;
+
+        let publisher = client.get_connection_manager().await.map_err(|e| {
+            log::error!("Failed to connect to Redis. Failed with error: {:?}", e);
+            anyhow::Error::msg("Failed to connect to Redis")
+        })?;
+
+        Ok(Self {
+            client,
+            publisher,
+            instance_id: ObjectId::new().to_hex(),
+        })
+    }
+
+    pub async fn publish(
+        &self,
+        room_id: &str,
+        payload: &str,
+        delivery: &Delivery,
+        kind: FrameKind,
+    ) {
+        let envelope = RoomEnvelope {
+            origin: self.instance_id.clone(),
+            room_id: room_id.to_string(),
+            payload: payload.to_string(),
+            delivery: delivery.clone(),
+            kind,
+            control: None,
+        };
+
+        self.send_envelope(&envelope).await;
+    }
+
+    pub async fn publish_control(&self, room_id: &str, payload: &str, control: RoomControl) {
+        let envelope = RoomEnvelope {
+            origin: self.instance_id.clone(),
+            room_id: room_id.to_string(),
+            payload: payload.to_string(),
+            delivery: Delivery::Everyone,
+            kind: FrameKind::Event,
+            control: Some(control),
+        };
+
+        self.send_envelope(&envelope).await;
+    }
+
+    async fn send_envelope(&self, envelope: &RoomEnvelope) {
+        let mut publisher = self.publisher.clone();
+
+        if let Err(error) = publisher
+            .publish::<_, _, ()>(
+                format!("{}{}", ROOM_CHANNEL_PREFIX, envelope.room_id),
+                serde_json::to_string(envelope).unwrap(),
+            )
+            .await
+        {
+            log::error!(
+                "Failed to publish message for room: {}. Failed with error: {:?}",
+                envelope.room_id,
+                error
+            );
+        }
+    }
+
+    pub async fn run_subscriber(self, room_users_collection: RoomUserMap) {
+        loop {
+            if let Err(error) = self.subscribe(room_users_collection.clone()).await {
+                log::error!("Redis subscription dropped. Failed with error: {:?}", error);
+            }
+
+            tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+
+    async fn subscribe(&self, room_users_collection: RoomUserMap) -> redis::RedisResult<()> {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub
+            .psubscribe(format!("{}*", ROOM_CHANNEL_PREFIX))
+            .await?;
+
+        log::info!(
+            "Subscribed to room channels as instance: {}",
+            self.instance_id
+        );
+
+        let mut messages = pubsub.on_message();
+
+        while let Some(msg) = messages.next().await {
+            let payload: String = match msg.get_payload() {
+                Ok(payload) => payload,
+                Err(err) => {
+                    log::error!(
+                        "Failed to read pub/sub payload. Failed with error: {:?}",
+                        err
+                    );
+                    continue;
+                }
+            };
+
+            let envelope = match serde_json::from_str::<RoomEnvelope>(&payload) {
+                Ok(envelope) => envelope,
+                Err(err) => {
+                    log::error!(
+                        "Failed to parse room envelope. Failed with error: {:?}",
+                        err
+                    );
+                    continue;
+                }
+            };
+
+            // Local sockets were already served by the publishing instance
+            if envelope.origin == self.instance_id {
+                continue;
+            }
+
+            match envelope.control {
+                Some(RoomControl::Disconnect { user_id }) => {
+                    disconnect_member(
+                        room_users_collection.clone(),
+                        &envelope.room_id,
+                        &user_id,
+                        &envelope.payload,
+                    )
+                    .await;
+                }
+                None => {
+                    deliver_message(
+                        room_users_collection.clone(),
+                        envelope.room_id,
+                        TokioMessage::Text(envelope.payload.into()),
+                        &envelope.delivery,
+                        envelope.kind,
+                    )
+                    .await;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
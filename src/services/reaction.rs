@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use actix_web::{HttpResponse, get, web};
+use futures_util::TryStreamExt;
+use mongodb::{
+    Database,
+    bson::{DateTime, doc, oid::ObjectId},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppState, RoomSync,
+    db::db::{Room, VideoReaction},
+    services::{message::MAX_EMOJI_LEN, video::get_current_sync_info},
+};
+
+const MAX_TIMELINE_REACTIONS: i64 = 5000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VideoReactionResponse {
+    pub id: String,
+    pub room_id: String,
+    pub user_id: String,
+    pub emoji: String,
+    pub position: f32,
+    // Milliseconds since the UNIX epoch
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeatmapBucket {
+    pub start: f32,
+    pub count: usize,
+    pub emojis: HashMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionTimelineResponse {
+    pub reactions: Vec<VideoReactionResponse>,
+    pub heatmap: Option<Vec<HeatmapBucket>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionTimelineQuery {
+    from: Option<f32>,
+    to: Option<f32>,
+    // Bucket width in seconds, a heatmap is only returned when set
+    bucket: Option<f32>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum VideoReactionError {
+    #[error("Invalid reaction")]
+    InvalidReaction,
+    #[error("Room has no playback state to anchor the reaction to")]
+    NoPlayback,
+    #[error("Failed to store the reaction")]
+    Internal,
+}
+
+impl VideoReactionError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            VideoReactionError::InvalidReaction => "invalid_reaction",
+            VideoReactionError::NoPlayback => "no_playback",
+            VideoReactionError::Internal => "internal_error",
+        }
+    }
+}
+
+fn to_reaction_response(reaction: VideoReaction) -> VideoReactionResponse {
+    VideoReactionResponse {
+        id: reaction.id.map(|id| id.to_hex()).unwrap_or_default(),
+        room_id: reaction.room_id,
+        user_id: reaction.user_id.to_hex(),
+        emoji: reaction.emoji,
+        position: reaction.position,
+        created_at: reaction.created_at.timestamp_millis(),
+    }
+}
+
+// Anchors the reaction to where the room's playback is at the moment it arrives
+pub async fn add_video_reaction(
+    db: Database,
+    room_sync: RoomSync,
+    room_id: String,
+    user: ObjectId,
+    emoji: String,
+) -> Result<VideoReactionResponse, VideoReactionError> {
+    if emoji.is_empty() || emoji.len() > MAX_EMOJI_LEN {
+        return Err(VideoReactionError::InvalidReaction);
+    }
+
+    let sync_info = get_current_sync_info(room_id.clone(), room_sync)
+        .await
+        .ok_or(VideoReactionError::NoPlayback)?;
+
+    let reaction = VideoReaction {
+        id: Some(ObjectId::new()),
+        room_id,
+        user_id: user,
+        emoji,
+        position: sync_info.time,
+        created_at: DateTime::now(),
+    };
+
+    if let Err(err) = db
+        .collection::<VideoReaction>("video_reactions")
+        .insert_one(reaction.clone())
+        .await
+    {
+        log::error!(
+            "Failed to insert video reaction. Failed with error: {:?}",
+            err
+        );
+
+        return Err(VideoReactionError::Internal);
+    }
+
+    Ok(to_reaction_response(reaction))
+}
+
+fn build_heatmap(reactions: &[VideoReactionResponse], bucket: f32) -> Vec<HeatmapBucket> {
+    let mut buckets: HashMap<i64, HeatmapBucket> = HashMap::new();
+
+    for reaction in reactions {
+        let index = (reaction.position / bucket).floor() as i64;
+
+        let entry = buckets.entry(index).or_insert_with(|| HeatmapBucket {
+            start: index as f32 * bucket,
+            count: 0,
+            emojis: HashMap::new(),
+        });
+
+        entry.count += 1;
+        *entry.emojis.entry(reaction.emoji.clone()).or_default() += 1;
+    }
+
+    let mut heatmap: Vec<HeatmapBucket> = buckets.into_values().collect();
+    heatmap.sort_by(|a, b| a.start.total_cmp(&b.start));
+    heatmap
+}
+
+#[get("/room/{room_id}/reactions")]
+pub async fn get_reaction_timeline(
+    path: web::Path<String>,
+    query: web::Query<ReactionTimelineQuery>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let room_id = path.into_inner();
+
+    match app_state
+        .db
+        .collection::<Room>("rooms")
+        .count_documents(doc! { "room_id": room_id.clone() })
+        .await
+    {
+        Ok(0) => return HttpResponse::NotFound().body("Room not found"),
+        Ok(_) => {}
+        Err(err) => {
+            log::error!("Failed to fetch room. Failed with error: {:?}", err);
+
+            return HttpResponse::InternalServerError().body("Failed to fetch room");
+        }
+    }
+
+    let mut position = doc! {};
+
+    if let Some(from) = query.from {
+        position.insert("$gte", from as f64);
+    }
+
+    if let Some(to) = query.to {
+        position.insert("$lte", to as f64);
+    }
+
+    let mut filter = doc! { "room_id": room_id };
+
+    if !position.is_empty() {
+        filter.insert("position", position);
+    }
+
+    let reactions: Vec<VideoReactionResponse> = match app_state
+        .db
+        .collection::<VideoReaction>("video_reactions")
+        .find(filter)
+        .sort(doc! { "position": 1 })
+        .limit(MAX_TIMELINE_REACTIONS)
+        .await
+    {
+        Ok(cursor) => match cursor.try_collect::<Vec<VideoReaction>>().await {
+            Ok(reactions) => reactions.into_iter().map(to_reaction_response).collect(),
+            Err(err) => {
+                log::error!(
+                    "Failed to read video reactions. Failed with error: {:?}",
+                    err
+                );
+
+                return HttpResponse::InternalServerError().body("Failed to read reactions");
+            }
+        },
+        Err(err) => {
+            log::error!(
+                "Failed to fetch video reactions. Failed with error: {:?}",
+                err
+            );
+
+            return HttpResponse::InternalServerError().body("Failed to fetch reactions");
+        }
+    };
+
+    let heatmap = query
+        .bucket
+        .filter(|bucket| bucket.is_finite() && *bucket > 0.0)
+        .map(|bucket| build_heatmap(&reactions, bucket));
+
+    HttpResponse::Ok().json(ReactionTimelineResponse { reactions, heatmap })
+}
@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+use mongodb::bson::doc;
+
+use crate::{AppState, db::db::Room, services::video::now_millis};
+
+#[derive(Debug, Default)]
+pub struct ReapReport {
+    pub sync_entries: usize,
+    pub room_maps: usize,
+    pub archived_rooms: usize,
+}
+
+pub async fn run_reaper(
+    app_state: AppState,
+    interval: Duration,
+    idle_timeout: Duration,
+    archive_rooms: bool,
+) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let report = reap_idle_rooms(&app_state, idle_timeout, archive_rooms).await;
+
+        log::info!(
+            "Reaper evicted {} sync entries and {} empty room maps, archived {} rooms",
+            report.sync_entries,
+            report.room_maps,
+            report.archived_rooms
+        );
+    }
+}
+
+// Other instances only see our sockets through this mark, so it has to outlast our next tick
+pub async fn mark_occupied(app_state: &AppState, room_id: &str) {
+    app_state
+        .room_sync
+        .mark_occupied(room_id, app_state.config.reaper_interval_secs * 2)
+        .await;
+}
+
+pub async fn reap_idle_rooms(
+    app_state: &AppState,
+    idle_timeout: Duration,
+    archive_rooms: bool,
+) -> ReapReport {
+    let mut report = ReapReport::default();
+
+    let cutoff = now_millis() - idle_timeout.as_millis() as f64;
+
+    {
+        let mut write_users_connection = app_state.room_users.write().await;
+        let before = write_users_connection.len();
+
+        write_users_connection.retain(|_, users| !users.is_empty());
+        report.room_maps = before - write_users_connection.len();
+    }
+
+    let local_rooms: Vec<String> = app_state.room_users.read().await.keys().cloned().collect();
+
+    for room_id in &local_rooms {
+        mark_occupied(app_state, room_id).await;
+    }
+
+    let room_collection = app_state.db.collection::<Room>("rooms");
+
+    for (room_id, sync_info) in app_state.room_sync.entries().await {
+        if sync_info.updated_at >= cutoff {
+            continue;
+        }
+
+        // Sync entries are shared, so sockets on other instances count too
+        if app_state.room_users.read().await.contains_key(&room_id)
+            || app_state.room_sync.is_occupied(&room_id).await
+        {
+            continue;
+        }
+
+        app_state.room_sync.remove(&room_id).await;
+        app_state.event_log.remove(&room_id).await;
+        report.sync_entries += 1;
+
+        if !archive_rooms {
+            continue;
+        }
+
+        match room_collection
+            .update_one(
+                doc! { "room_id": room_id.clone() },
+                doc! { "$set": { "archived": true } },
+            )
+            .await
+        {
+            Ok(_) => {
+                report.archived_rooms += 1;
+            }
+            Err(err) => {
+                log::error!(
+                    "Failed to archive room: {}. Failed with error: {:?}",
+                    room_id,
+                    err
+                );
+            }
+        }
+    }
+
+    report
+}
@@ -1,9 +1,13 @@
-use actix_web::{HttpResponse, post, web};
-use mongodb::bson::oid::ObjectId;
+use actix_web::{HttpResponse, get, post, web};
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    AppState, SyncInfo, actions::Platform, db::db::Room, services::video::set_sync_info,
+    AppState, SyncInfo,
+    actions::Platform,
+    db::db::{PlaybackControl, Room, User},
+    services::video::{get_current_sync_info, set_sync_info},
     ws_conn::VideoAction,
 };
 
@@ -16,6 +20,24 @@ pub struct RoomRequest {
     updated_at: f64,
     action: VideoAction,
     updated_by: String,
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default)]
+    control: PlaybackControl,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RoomDetailsResponse {
+    room_id: String,
+    platform: String,
+    users: Vec<User>,
+    host: Option<String>,
+    co_hosts: Vec<String>,
+    control: PlaybackControl,
+    archived: bool,
+    sync_info: Option<SyncInfo>,
+    // Sockets held by this instance
+    connected: usize,
 }
 
 #[post("/room/create")]
@@ -33,12 +55,25 @@ pub async fn create_new_room(
         .map(|id_str| ObjectId::parse_str(id_str).unwrap())
         .collect();
 
+    // Without an explicit host the first listed member runs the room
+    let host = match &req.host {
+        Some(host) => match ObjectId::parse_str(host) {
+            Ok(host) => Some(host),
+            Err(_) => return HttpResponse::BadRequest().body("Invalid host id"),
+        },
+        None => user_ids.first().copied(),
+    };
+
     let room_details = Room {
         id,
         room_id: req.room_id.clone(),
         users: user_ids,
-        messages: Vec::new(),
         platform: serde_json::to_string(&req.platform).unwrap(),
+        archived: false,
+        host,
+        co_hosts: Vec::new(),
+        control: req.control,
+        banned: Vec::new(),
     };
 
     room_collection
@@ -46,19 +81,78 @@ pub async fn create_new_room(
         .await
         .unwrap();
 
-    log::info!(
-        "New room created with ID: {}",
-        room_details.room_id.to_string()
-    );
+    log::info!("New room created with ID: {}", room_details.room_id);
 
     let sync_info = SyncInfo {
         last_action: req.action.clone(),
         time: req.time,
         updated_at: req.updated_at,
         updated_by: req.updated_by.clone(),
+        rate: 1.0,
+        playing: matches!(req.action, VideoAction::Play),
     };
 
     set_sync_info(req.room_id.clone(), sync_info, app_state.room_sync.clone()).await;
 
     HttpResponse::Ok().body(req.room_id.clone())
 }
+
+#[get("/room/{room_id}")]
+pub async fn get_room_details(
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let room_id = path.into_inner();
+
+    let room_collection = app_state.db.collection::<Room>("rooms");
+    let user_collection = app_state.db.collection::<User>("users");
+
+    let room = match room_collection
+        .find_one(doc! { "room_id": room_id.clone() })
+        .await
+    {
+        Ok(Some(room)) => room,
+        Ok(None) => return HttpResponse::NotFound().body("Room not found"),
+        Err(error) => {
+            log::error!("Failed to fetch room. Failed with error: {:?}", error);
+
+            return HttpResponse::InternalServerError().body("Failed to fetch room");
+        }
+    };
+
+    let users: Vec<User> = match user_collection
+        .find(doc! { "_id": { "$in": room.users.clone() } })
+        .await
+    {
+        Ok(cursor) => cursor.try_collect().await.unwrap_or_else(|error| {
+            log::error!("Failed to read room users. Failed with error: {:?}", error);
+            Vec::new()
+        }),
+        Err(error) => {
+            log::error!("Failed to fetch room users. Failed with error: {:?}", error);
+
+            return HttpResponse::InternalServerError().body("Failed to fetch room users");
+        }
+    };
+
+    let sync_info = get_current_sync_info(room_id.clone(), app_state.room_sync.clone()).await;
+
+    let connected = app_state
+        .room_users
+        .read()
+        .await
+        .get(&room_id)
+        .map_or(0, |room_map| room_map.len());
+
+    HttpResponse::Ok().json(RoomDetailsResponse {
+        room_id: room.room_id,
+        platform: room.platform,
+        users,
+        host: room.host.map(|host| host.to_hex()),
+        co_hosts: room.co_hosts.iter().map(|id| id.to_hex()).collect(),
+        control: room.control,
+        archived: room.archived,
+        sync_info,
+        connected,
+    })
+}
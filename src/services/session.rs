@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use redis::{AsyncCommands, Script, aio::ConnectionManager};
+use tokio::sync::RwLock;
+
+const SESSION_KEY_PREFIX: &str = "session:";
+const MEMBER_KEY_PREFIX: &str = "session:member:";
+
+// Hands the session to the detaching connection's grace timer, if that connection still owns it
+const DETACH_SCRIPT: &str = r"
+if redis.call('HGET', KEYS[1], 'connection') ~= ARGV[1] then
+    return false
+end
+redis.call('HSET', KEYS[1], 'detached', '1')
+local generation = redis.call('HINCRBY', KEYS[1], 'generation', 1)
+local member = redis.call('HMGET', KEYS[1], 'room_id', 'user_id')
+return {member[1], member[2], generation}
+";
+
+const RESUME_SCRIPT: &str = r"
+if redis.call('HGET', KEYS[1], 'user_id') ~= ARGV[1] then
+    return false
+end
+redis.call('HSET', KEYS[1], 'connection', ARGV[2], 'detached', '0')
+local generation = redis.call('HINCRBY', KEYS[1], 'generation', 1)
+redis.call('EXPIRE', KEYS[1], ARGV[3])
+return {redis.call('HGET', KEYS[1], 'room_id'), generation}
+";
+
+// Returns 1 only when the session lapsed and was still the member's latest one
+const EXPIRE_SCRIPT: &str = r"
+if redis.call('HGET', KEYS[1], 'detached') ~= '1'
+    or redis.call('HGET', KEYS[1], 'generation') ~= ARGV[1] then
+    return 0
+end
+redis.call('DEL', KEYS[1])
+if redis.call('GET', KEYS[2]) ~= ARGV[2] then
+    return 0
+end
+redis.call('DEL', KEYS[2])
+return 1
+";
+
+const END_SCRIPT: &str = r"
+if redis.call('HGET', KEYS[1], 'connection') == ARGV[1] then
+    redis.call('DEL', KEYS[1])
+end
+";
+
+// A member's place in a room, which outlives any single socket for the grace period
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub session_id: String,
+    pub room_id: String,
+    pub user_id: String,
+    // Bumped on every detach and resume so a stale grace timer can tell it lost the race
+    pub generation: u64,
+}
+
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    // Replaces any earlier session of the member in that room
+    async fn start(&self, room_id: &str, user_id: &str, connection_id: u64) -> String;
+    // None when another socket owns the session by now
+    async fn detach(&self, session_id: &str, connection_id: u64) -> Option<Session>;
+    // Hands the session to a new socket of the same user
+    async fn resume(&self, session_id: &str, user_id: &str, connection_id: u64) -> Option<Session>;
+    // True when nobody resumed the session and the member has not joined again since
+    async fn expire(&self, session: &Session) -> bool;
+    async fn end(&self, session_id: &str, connection_id: u64);
+    // Drops the member's latest session whichever socket owns it, so a removed member cannot resume
+    async fn end_member(&self, room_id: &str, user_id: &str);
+}
+
+fn member_key(room_id: &str, user_id: &str) -> String {
+    format!("{}:{}", room_id, user_id)
+}
+
+struct MemorySession {
+    session: Session,
+    connection_id: u64,
+    detached: bool,
+}
+
+#[derive(Default)]
+struct MemorySessions {
+    sessions: HashMap<String, MemorySession>,
+    // room and user -> latest session id
+    members: HashMap<String, String>,
+}
+
+#[derive(Default)]
+pub struct MemorySessionStore {
+    state: RwLock<MemorySessions>,
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn start(&self, room_id: &str, user_id: &str, connection_id: u64) -> String {
+        let session_id = ObjectId::new().to_hex();
+        let mut write_state = self.state.write().await;
+
+        write_state.sessions.insert(
+            session_id.clone(),
+            MemorySession {
+                session: Session {
+                    session_id: session_id.clone(),
+                    room_id: room_id.to_string(),
+                    user_id: user_id.to_string(),
+                    generation: 0,
+                },
+                connection_id,
+                detached: false,
+            },
+        );
+        write_state
+            .members
+            .insert(member_key(room_id, user_id), session_id.clone());
+
+        session_id
+    }
+
+    async fn detach(&self, session_id: &str, connection_id: u64) -> Option<Session> {
+        let mut write_state = self.state.write().await;
+        let entry = write_state.sessions.get_mut(session_id)?;
+
+        if entry.connection_id != connection_id {
+            return None;
+        }
+
+        entry.detached = true;
+        entry.session.generation += 1;
+
+        Some(entry.session.clone())
+    }
+
+    async fn resume(&self, session_id: &str, user_id: &str, connection_id: u64) -> Option<Session> {
+        let mut write_state = self.state.write().await;
+        let entry = write_state.sessions.get_mut(session_id)?;
+
+        if entry.session.user_id != user_id {
+            return None;
+        }
+
+        entry.connection_id = connection_id;
+        entry.detached = false;
+        entry.session.generation += 1;
+
+        Some(entry.session.clone())
+    }
+
+    async fn expire(&self, session: &Session) -> bool {
+        let mut write_state = self.state.write().await;
+
+        if !write_state
+            .sessions
+            .get(&session.session_id)
+            .is_some_and(|entry| entry.detached && entry.session.generation == session.generation)
+        {
+            return false;
+        }
+
+        write_state.sessions.remove(&session.session_id);
+
+        let member = member_key(&session.room_id, &session.user_id);
+
+        if write_state.members.get(&member) != Some(&session.session_id) {
+            return false;
+        }
+
+        write_state.members.remove(&member);
+        true
+    }
+
+    async fn end(&self, session_id: &str, connection_id: u64) {
+        let mut write_state = self.state.write().await;
+
+        if write_state
+            .sessions
+            .get(session_id)
+            .is_some_and(|entry| entry.connection_id == connection_id)
+        {
+            write_state.sessions.remove(session_id);
+        }
+    }
+
+    async fn end_member(&self, room_id: &str, user_id: &str) {
+        let mut write_state = self.state.write().await;
+
+        if let Some(session_id) = write_state.members.remove(&member_key(room_id, user_id)) {
+            write_state.sessions.remove(&session_id);
+        }
+    }
+}
+
+// Lets a client resume on any instance, and keeps a grace timer from announcing a member who came back elsewhere
+pub struct RedisSessionStore {
+    conn: ConnectionManager,
+    ttl_secs: u64,
+    // Connection ids are only unique per process
+    instance_id: String,
+    fallback: MemorySessionStore,
+}
+
+impl RedisSessionStore {
+    pub async fn connect(redis_url: &str, ttl_secs: u64) -> Result<Self, anyhow::Error> {
+        let client = redis::Client::open(redis_url).map_err(|e| {
+            log::error!("Invalid Redis URL. Failed with error: {:?}", e);
+            anyhow::Error::msg("Invalid Redis URL")
+        })?;
+
+        let conn = client.get_connection_manager().await.map_err(|e| {
+            log::error!("Failed to connect to Redis. Failed with error: {:?}", e);
+            anyhow::Error::msg("Failed to connect to Redis")
+        })?;
+
+        Ok(Self {
+            conn,
+            ttl_secs,
+            instance_id: ObjectId::new().to_hex(),
+            fallback: MemorySessionStore::default(),
+        })
+    }
+
+    fn session_key(session_id: &str) -> String {
+        format!("{}{}", SESSION_KEY_PREFIX, session_id)
+    }
+
+    fn member_key(room_id: &str, user_id: &str) -> String {
+        format!("{}{}", MEMBER_KEY_PREFIX, member_key(room_id, user_id))
+    }
+
+    fn connection(&self, connection_id: u64) -> String {
+        format!("{}:{}", self.instance_id, connection_id)
+    }
+
+    async fn try_start(
+        &self,
+        session_id: &str,
+        room_id: &str,
+        user_id: &str,
+        connection_id: u64,
+    ) -> redis::RedisResult<()> {
+        let mut conn = self.conn.clone();
+
+        redis::pipe()
+            .hset_multiple(
+                Self::session_key(session_id),
+                &[
+                    ("room_id", room_id.to_string()),
+                    ("user_id", user_id.to_string()),
+                    ("connection", self.connection(connection_id)),
+                    ("detached", "0".to_string()),
+                    ("generation", "0".to_string()),
+                ],
+            )
+            .expire(Self::session_key(session_id), self.ttl_secs as i64)
+            .set_ex(
+                Self::member_key(room_id, user_id),
+                session_id,
+                self.ttl_secs,
+            )
+            .query_async::<()>(&mut conn)
+            .await
+    }
+
+    async fn try_end_member(&self, room_id: &str, user_id: &str) -> redis::RedisResult<()> {
+        let mut conn = self.conn.clone();
+
+        let session_id: Option<String> = conn.get(Self::member_key(room_id, user_id)).await?;
+
+        if let Some(session_id) = session_id {
+            conn.del::<_, ()>(&[
+                Self::session_key(&session_id),
+                Self::member_key(room_id, user_id),
+            ])
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn try_resume(
+        &self,
+        session_id: &str,
+        user_id: &str,
+        connection_id: u64,
+    ) -> redis::RedisResult<Option<Session>> {
+        let mut conn = self.conn.clone();
+
+        let resumed: Option<(String, u64)> = Script::new(RESUME_SCRIPT)
+            .key(Self::session_key(session_id))
+            .arg(user_id)
+            .arg(self.connection(connection_id))
+            .arg(self.ttl_secs)
+            .invoke_async(&mut conn)
+            .await?;
+
+        let Some((room_id, generation)) = resumed else {
+            return Ok(None);
+        };
+
+        conn.expire::<_, ()>(Self::member_key(&room_id, user_id), self.ttl_secs as i64)
+            .await?;
+
+        Ok(Some(Session {
+            session_id: session_id.to_string(),
+            room_id,
+            user_id: user_id.to_string(),
+            generation,
+        }))
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisSessionStore {
+    async fn start(&self, room_id: &str, user_id: &str, connection_id: u64) -> String {
+        let session_id = ObjectId::new().to_hex();
+
+        match self
+            .try_start(&session_id, room_id, user_id, connection_id)
+            .await
+        {
+            Ok(()) => session_id,
+            Err(err) => {
+                log::error!(
+                    "Failed to store the session in Redis. Failed with error: {:?}",
+                    err
+                );
+                self.fallback.start(room_id, user_id, connection_id).await
+            }
+        }
+    }
+
+    async fn detach(&self, session_id: &str, connection_id: u64) -> Option<Session> {
+        let mut conn = self.conn.clone();
+
+        let detached: redis::RedisResult<Option<(String, String, u64)>> =
+            Script::new(DETACH_SCRIPT)
+                .key(Self::session_key(session_id))
+                .arg(self.connection(connection_id))
+                .invoke_async(&mut conn)
+                .await;
+
+        match detached {
+            Ok(Some((room_id, user_id, generation))) => Some(Session {
+                session_id: session_id.to_string(),
+                room_id,
+                user_id,
+                generation,
+            }),
+            Ok(None) => self.fallback.detach(session_id, connection_id).await,
+            Err(err) => {
+                log::error!(
+                    "Failed to detach the session in Redis. Failed with error: {:?}",
+                    err
+                );
+                self.fallback.detach(session_id, connection_id).await
+            }
+        }
+    }
+
+    async fn resume(&self, session_id: &str, user_id: &str, connection_id: u64) -> Option<Session> {
+        match self.try_resume(session_id, user_id, connection_id).await {
+            Ok(Some(session)) => Some(session),
+            Ok(None) => {
+                self.fallback
+                    .resume(session_id, user_id, connection_id)
+                    .await
+            }
+            Err(err) => {
+                log::error!(
+                    "Failed to resume the session in Redis. Failed with error: {:?}",
+                    err
+                );
+                self.fallback
+                    .resume(session_id, user_id, connection_id)
+                    .await
+            }
+        }
+    }
+
+    async fn expire(&self, session: &Session) -> bool {
+        let mut conn = self.conn.clone();
+
+        let expired: redis::RedisResult<u8> = Script::new(EXPIRE_SCRIPT)
+            .key(Self::session_key(&session.session_id))
+            .key(Self::member_key(&session.room_id, &session.user_id))
+            .arg(session.generation)
+            .arg(&session.session_id)
+            .invoke_async(&mut conn)
+            .await;
+
+        match expired {
+            Ok(expired) => expired == 1 || self.fallback.expire(session).await,
+            Err(err) => {
+                log::error!(
+                    "Failed to expire the session in Redis. Failed with error: {:?}",
+                    err
+                );
+                self.fallback.expire(session).await
+            }
+        }
+    }
+
+    async fn end(&self, session_id: &str, connection_id: u64) {
+        let mut conn = self.conn.clone();
+
+        if let Err(err) = Script::new(END_SCRIPT)
+            .key(Self::session_key(session_id))
+            .arg(self.connection(connection_id))
+            .invoke_async::<()>(&mut conn)
+            .await
+        {
+            log::error!(
+                "Failed to end the session in Redis. Failed with error: {:?}",
+                err
+            );
+        }
+
+        self.fallback.end(session_id, connection_id).await;
+    }
+
+    async fn end_member(&self, room_id: &str, user_id: &str) {
+        if let Err(err) = self.try_end_member(room_id, user_id).await {
+            log::error!(
+                "Failed to end the member's session in Redis. Failed with error: {:?}",
+                err
+            );
+        }
+
+        self.fallback.end_member(room_id, user_id).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn detached_session_lapses_after_the_grace_period() {
+        let store = MemorySessionStore::default();
+        let session_id = store.start("room", "user", 1).await;
+
+        let detached = store.detach(&session_id, 1).await.unwrap();
+
+        assert!(store.expire(&detached).await);
+        assert!(store.resume(&session_id, "user", 2).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn resumed_session_outlives_the_stale_grace_timer() {
+        let store = MemorySessionStore::default();
+        let session_id = store.start("room", "user", 1).await;
+
+        let detached = store.detach(&session_id, 1).await.unwrap();
+        let resumed = store.resume(&session_id, "user", 2).await.unwrap();
+
+        assert!(resumed.generation > detached.generation);
+        assert!(!store.expire(&detached).await);
+        // The old socket no longer owns it
+        assert!(store.detach(&session_id, 1).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejoining_keeps_the_old_session_from_announcing_a_leave() {
+        let store = MemorySessionStore::default();
+        let first = store.start("room", "user", 1).await;
+
+        let detached = store.detach(&first, 1).await.unwrap();
+        store.start("room", "user", 2).await;
+
+        assert!(!store.expire(&detached).await);
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_can_resume_or_end_a_session() {
+        let store = MemorySessionStore::default();
+        let session_id = store.start("room", "user", 1).await;
+
+        assert!(store.resume(&session_id, "someone_else", 2).await.is_none());
+
+        store.end(&session_id, 2).await;
+        assert!(store.detach(&session_id, 1).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn removed_member_cannot_resume() {
+        let store = MemorySessionStore::default();
+        let session_id = store.start("room", "user", 1).await;
+
+        store.detach(&session_id, 1).await;
+        store.end_member("room", "user").await;
+
+        assert!(store.resume(&session_id, "user", 2).await.is_none());
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use redis::{AsyncCommands, aio::ConnectionManager};
+use tokio::sync::RwLock;
+
+use crate::ws_conn::SyncInfo;
+
+const SYNC_KEY_PREFIX: &str = "sync:";
+const OCCUPIED_KEY_PREFIX: &str = "occupied:";
+
+#[async_trait]
+pub trait SyncStore: Send + Sync {
+    async fn get(&self, room_id: &str) -> Option<SyncInfo>;
+    async fn set(&self, room_id: &str, sync_info: SyncInfo);
+    async fn remove(&self, room_id: &str);
+    async fn entries(&self) -> Vec<(String, SyncInfo)>;
+    // Records that some instance holds sockets in the room, until the TTL runs out
+    async fn mark_occupied(&self, room_id: &str, ttl_secs: u64);
+    async fn is_occupied(&self, room_id: &str) -> bool;
+}
+
+#[derive(Default)]
+pub struct MemorySyncStore {
+    rooms: RwLock<HashMap<String, SyncInfo>>,
+    // room -> when the occupancy mark lapses
+    occupied: RwLock<HashMap<String, Instant>>,
+}
+
+#[async_trait]
+impl SyncStore for MemorySyncStore {
+    async fn get(&self, room_id: &str) -> Option<SyncInfo> {
+        self.rooms.read().await.get(room_id).cloned()
+    }
+
+    async fn set(&self, room_id: &str, sync_info: SyncInfo) {
+        self.rooms
+            .write()
+            .await
+            .insert(room_id.to_string(), sync_info);
+    }
+
+    async fn remove(&self, room_id: &str) {
+        self.rooms.write().await.remove(room_id);
+    }
+
+    async fn entries(&self) -> Vec<(String, SyncInfo)> {
+        self.rooms
+            .read()
+            .await
+            .iter()
+            .map(|(room_id, sync_info)| (room_id.clone(), sync_info.clone()))
+            .collect()
+    }
+
+    async fn mark_occupied(&self, room_id: &str, ttl_secs: u64) {
+        let mut write_occupied = self.occupied.write().await;
+        let now = Instant::now();
+
+        write_occupied.retain(|_, until| *until > now);
+        write_occupied.insert(room_id.to_string(), now + Duration::from_secs(ttl_secs));
+    }
+
+    async fn is_occupied(&self, room_id: &str) -> bool {
+        self.occupied
+            .read()
+            .await
+            .get(room_id)
+            .is_some_and(|until| *until > Instant::now())
+    }
+}
+
+// Keeps sync state in Redis with a TTL, falling back to memory while Redis is unreachable
+pub struct RedisSyncStore {
+    conn: ConnectionManager,
+    ttl_secs: u64,
+    fallback: MemorySyncStore,
+}
+
+impl RedisSyncStore {
+    pub async fn connect(redis_url: &str, ttl_secs: u64) -> Result<Self, anyhow::Error> {
+        let client = redis::Client::open(redis_url).map_err(|e| {
+            log::error!("Invalid Redis URL. Failed with error: {:?}", e);
+            anyhow::Error::msg("Invalid Redis URL")
+        })?;
+
+        let conn = client.get_connection_manager().await.map_err(|e| {
+            log::error!("Failed to connect to Redis. Failed with error: {:?}", e);
+            anyhow::Error::msg("Failed to connect to Redis")
+        })?;
+
+        Ok(Self {
+            conn,
+            ttl_secs,
+            fallback: MemorySyncStore::default(),
+        })
+    }
+
+    fn key(room_id: &str) -> String {
+        format!("{}{}", SYNC_KEY_PREFIX, room_id)
+    }
+
+    fn occupied_key(room_id: &str) -> String {
+        format!("{}{}", OCCUPIED_KEY_PREFIX, room_id)
+    }
+}
+
+#[async_trait]
+impl SyncStore for RedisSyncStore {
+    async fn get(&self, room_id: &str) -> Option<SyncInfo> {
+        let mut conn = self.conn.clone();
+
+        match conn.get::<_, Option<String>>(Self::key(room_id)).await {
+            Ok(Some(value)) => match serde_json::from_str::<SyncInfo>(&value) {
+                Ok(sync_info) => Some(sync_info),
+                Err(err) => {
+                    log::error!(
+                        "Failed to parse sync info for room: {}. Failed with error: {:?}",
+                        room_id,
+                        err
+                    );
+                    None
+                }
+            },
+            Ok(None) => self.fallback.get(room_id).await,
+            Err(err) => {
+                log::error!(
+                    "Failed to read sync info from Redis. Failed with error: {:?}",
+                    err
+                );
+                self.fallback.get(room_id).await
+            }
+        }
+    }
+
+    async fn set(&self, room_id: &str, sync_info: SyncInfo) {
+        let mut conn = self.conn.clone();
+
+        match conn
+            .set_ex::<_, _, ()>(
+                Self::key(room_id),
+                serde_json::to_string(&sync_info).unwrap(),
+                self.ttl_secs,
+            )
+            .await
+        {
+            Ok(()) => self.fallback.remove(room_id).await,
+            Err(err) => {
+                log::error!(
+                    "Failed to write sync info to Redis. Failed with error: {:?}",
+                    err
+                );
+                self.fallback.set(room_id, sync_info).await;
+            }
+        }
+    }
+
+    async fn remove(&self, room_id: &str) {
+        let mut conn = self.conn.clone();
+
+        if let Err(err) = conn.del::<_, ()>(Self::key(room_id)).await {
+            log::error!(
+                "Failed to delete sync info from Redis. Failed with error: {:?}",
+                err
+            );
+        }
+
+        self.fallback.remove(room_id).await;
+    }
+
+    async fn entries(&self) -> Vec<(String, SyncInfo)> {
+        let mut conn = self.conn.clone();
+        let mut entries = self.fallback.entries().await;
+
+        let keys: Vec<String> = match conn
+            .scan_match::<_, String>(format!("{}*", SYNC_KEY_PREFIX))
+            .await
+        {
+            Ok(iter) => iter.collect().await,
+            Err(err) => {
+                log::error!(
+                    "Failed to scan sync info in Redis. Failed with error: {:?}",
+                    err
+                );
+                return entries;
+            }
+        };
+
+        for key in keys {
+            let Some(room_id) = key.strip_prefix(SYNC_KEY_PREFIX) else {
+                continue;
+            };
+
+            if let Some(sync_info) = self.get(room_id).await {
+                entries.push((room_id.to_string(), sync_info));
+            }
+        }
+
+        entries
+    }
+
+    async fn mark_occupied(&self, room_id: &str, ttl_secs: u64) {
+        let mut conn = self.conn.clone();
+
+        if let Err(err) = conn
+            .set_ex::<_, _, ()>(Self::occupied_key(room_id), 1, ttl_secs)
+            .await
+        {
+            log::error!(
+                "Failed to mark room: {} occupied in Redis. Failed with error: {:?}",
+                room_id,
+                err
+            );
+            self.fallback.mark_occupied(room_id, ttl_secs).await;
+        }
+    }
+
+    async fn is_occupied(&self, room_id: &str) -> bool {
+        let mut conn = self.conn.clone();
+
+        match conn.exists::<_, bool>(Self::occupied_key(room_id)).await {
+            Ok(occupied) => occupied || self.fallback.is_occupied(room_id).await,
+            Err(err) => {
+                log::error!(
+                    "Failed to read room occupancy from Redis. Failed with error: {:?}",
+                    err
+                );
+                // Keeping an idle room a while longer is cheaper than evicting a live one
+                true
+            }
+        }
+    }
+}
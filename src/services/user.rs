@@ -1,4 +1,4 @@
-use crate::{AppState, db::db::User};
+use crate::{AppState, auth::issue_session_token, db::db::User};
 use actix_web::{HttpResponse, post, web};
 use mongodb::{bson::doc, bson::oid::ObjectId};
 use names::Generator;
@@ -10,6 +10,13 @@ pub struct CreateUserRequest {
     avatar: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct CreateUserResponse {
+    #[serde(flatten)]
+    user: User,
+    token: String,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct AddNewUserRequest {
     room_id: String,
@@ -29,7 +36,7 @@ pub async fn create_new_user(
     let user_id = ObjectId::new();
 
     let user = User {
-        id: Some(user_id.clone()),
+        id: Some(user_id),
         username: username.clone(),
         name: req.name.clone(),
         avatar: req.avatar.clone(),
@@ -41,12 +48,25 @@ pub async fn create_new_user(
 
             let user = User {
                 id: Some(user_id),
-                username: username,
+                username,
                 name: req.name.clone(),
                 avatar: req.avatar.clone(),
             };
 
-            HttpResponse::Ok().json(serde_json::to_string(&user).unwrap())
+            let token = match issue_session_token(
+                &user_id.to_hex(),
+                &app_state.config.session_secret,
+                app_state.config.session_ttl_secs,
+            ) {
+                Ok(token) => token,
+                Err(_) => {
+                    return HttpResponse::InternalServerError()
+                        .body("Failed to issue session token");
+                }
+            };
+
+            HttpResponse::Ok()
+                .json(serde_json::to_string(&CreateUserResponse { user, token }).unwrap())
         }
         Err(error) => {
             log::error!(
@@ -1,20 +1,262 @@
-use crate::{RoomSync, ws_conn::SyncInfo};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub async fn set_sync_info(room_id: String, sync_info: SyncInfo, room_sync: RoomSync) {
-    let mut room_sync_write = room_sync.write().await;
+use crate::{
+    RoomBuffering, RoomSync,
+    ws_conn::{SyncInfo, VideoAction},
+};
 
+// Milliseconds since the UNIX epoch, the unit of `SyncInfo::updated_at`
+pub fn now_millis() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as f64
+}
+
+// Stamps the action with the server clock, client supplied timestamps are ignored
+pub async fn set_sync_info(room_id: String, sync_info: SyncInfo, room_sync: RoomSync) -> SyncInfo {
     let sync_info = SyncInfo {
         last_action: sync_info.last_action.clone(),
         time: sync_info.time,
-        updated_at: sync_info.updated_at,
+        updated_at: now_millis(),
         updated_by: sync_info.updated_by.clone(),
+        rate: sync_info.rate,
+        playing: sync_info.playing,
     };
 
-    room_sync_write.insert(room_id.clone(), sync_info);
+    room_sync.set(&room_id, sync_info.clone()).await;
+
+    sync_info
 }
 
 pub async fn get_sync_info(room_id: String, room_sync: RoomSync) -> Option<SyncInfo> {
-    let room_sync_write = room_sync.read().await;
+    room_sync.get(&room_id).await
+}
+
+// Position the room is at right now, rather than when the last action happened
+pub async fn get_current_sync_info(room_id: String, room_sync: RoomSync) -> Option<SyncInfo> {
+    get_sync_info(room_id, room_sync)
+        .await
+        .map(|sync_info| extrapolate_sync_info(&sync_info, now_millis()))
+}
+
+// Leads the position by the one way delay so it is current when the client receives it
+pub async fn get_sync_info_for_client(
+    room_id: String,
+    room_sync: RoomSync,
+    rtt_ms: Option<f64>,
+) -> Option<SyncInfo> {
+    let one_way_ms = rtt_ms.unwrap_or_default() / 2.0;
+
+    get_sync_info(room_id, room_sync)
+        .await
+        .map(|sync_info| extrapolate_sync_info(&sync_info, now_millis() + one_way_ms))
+}
+
+// Moves the playback state in a broadcast frame forward to `at`, leaving the rest of the frame as is
+pub fn lead_sync_frame(frame: &str, at: f64) -> Option<String> {
+    let serde_json::Value::Object(mut frame) = serde_json::from_str(frame).ok()? else {
+        return None;
+    };
+
+    let sync_info = serde_json::from_value::<SyncInfo>(frame.get("data")?.clone()).ok()?;
+
+    frame.insert(
+        "data".to_string(),
+        serde_json::to_value(extrapolate_sync_info(&sync_info, at)).ok()?,
+    );
+
+    Some(serde_json::Value::Object(frame).to_string())
+}
+
+pub fn extrapolate_sync_info(sync_info: &SyncInfo, now: f64) -> SyncInfo {
+    let mut current = sync_info.clone();
+
+    if sync_info.playing {
+        let elapsed_secs = ((now - sync_info.updated_at) / 1000.0).max(0.0);
+        current.time = sync_info.time + (elapsed_secs as f32) * sync_info.rate;
+    }
+
+    current.updated_at = now;
+    current
+}
+
+// Applies a playback action on top of the room's current state and stores the result
+pub async fn apply_video_action(
+    room_id: String,
+    action: VideoAction,
+    time: f32,
+    updated_by: String,
+    room_sync: RoomSync,
+) -> SyncInfo {
+    let current = get_current_sync_info(room_id.clone(), room_sync.clone()).await;
+
+    let was_playing = current.as_ref().is_some_and(|sync_info| sync_info.playing);
+    let current_time = current.as_ref().map_or(time, |sync_info| sync_info.time);
+    let current_rate = current.as_ref().map_or(1.0, |sync_info| sync_info.rate);
+
+    let (time, rate, playing) = match &action {
+        VideoAction::Play => (time, current_rate, true),
+        VideoAction::Pause => (time, current_rate, false),
+        VideoAction::Skip => (time, current_rate, was_playing),
+        VideoAction::Seek { to } => (*to, current_rate, was_playing),
+        VideoAction::SetRate { rate } => (time, *rate, was_playing),
+        VideoAction::Buffering => (current_time, current_rate, false),
+        VideoAction::Ready => (current_time, current_rate, true),
+    };
+
+    let sync_info = SyncInfo {
+        last_action: action,
+        time,
+        updated_at: now_millis(),
+        updated_by,
+        rate,
+        playing,
+    };
+
+    set_sync_info(room_id, sync_info, room_sync).await
+}
+
+// Auto-pauses the room when the first member starts buffering during playback
+pub async fn report_buffering(
+    room_id: String,
+    user_id: String,
+    room_sync: RoomSync,
+    room_buffering: RoomBuffering,
+) -> Option<SyncInfo> {
+    let mut write_buffering = room_buffering.write().await;
+
+    let state = write_buffering.entry(room_id.clone()).or_default();
+    let first_to_buffer = state.users.is_empty();
+    state.users.insert(user_id.clone());
+
+    if !first_to_buffer {
+        return None;
+    }
+
+    let current = get_current_sync_info(room_id.clone(), room_sync.clone()).await?;
+
+    if !current.playing {
+        return None;
+    }
+
+    state.resume_on_ready = true;
+
+    Some(
+        apply_video_action(
+            room_id,
+            VideoAction::Buffering,
+            current.time,
+            user_id,
+            room_sync,
+        )
+        .await,
+    )
+}
+
+// Resumes an auto-paused room once every buffering member is ready again
+pub async fn report_ready(
+    room_id: String,
+    user_id: String,
+    room_sync: RoomSync,
+    room_buffering: RoomBuffering,
+) -> Option<SyncInfo> {
+    let mut write_buffering = room_buffering.write().await;
+
+    let state = write_buffering.get_mut(&room_id)?;
+    state.users.remove(&user_id);
+
+    if !state.users.is_empty() {
+        return None;
+    }
+
+    let resume = state.resume_on_ready;
+    write_buffering.remove(&room_id);
+
+    if !resume {
+        return None;
+    }
+
+    let current = get_current_sync_info(room_id.clone(), room_sync.clone()).await?;
+
+    Some(
+        apply_video_action(
+            room_id,
+            VideoAction::Ready,
+            current.time,
+            user_id,
+            room_sync,
+        )
+        .await,
+    )
+}
+
+// An explicit play or pause overrides any pending auto-resume
+pub async fn cancel_auto_resume(room_id: &str, room_buffering: RoomBuffering) {
+    if let Some(state) = room_buffering.write().await.get_mut(room_id) {
+        state.resume_on_ready = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sync_info(playing: bool, rate: f32) -> SyncInfo {
+        SyncInfo {
+            last_action: VideoAction::Play,
+            time: 10.0,
+            updated_at: 1_000.0,
+            updated_by: "host".to_string(),
+            rate,
+            playing,
+        }
+    }
+
+    #[test]
+    fn playing_room_advances_by_rate() {
+        let current = extrapolate_sync_info(&sync_info(true, 1.5), 5_000.0);
+
+        assert_eq!(current.time, 16.0);
+        assert_eq!(current.updated_at, 5_000.0);
+        assert!(current.playing);
+    }
+
+    #[test]
+    fn paused_room_keeps_its_position() {
+        let current = extrapolate_sync_info(&sync_info(false, 2.0), 5_000.0);
+
+        assert_eq!(current.time, 10.0);
+        assert_eq!(current.updated_at, 5_000.0);
+    }
+
+    #[test]
+    fn leads_sync_frames_and_keeps_their_other_fields() {
+        let frame = serde_json::json!({
+            "response_type": "VideoAction",
+            "data": sync_info(true, 1.0),
+            "seq": 4,
+        });
+
+        let led: serde_json::Value =
+            serde_json::from_str(&lead_sync_frame(&frame.to_string(), 3_000.0).unwrap()).unwrap();
+
+        assert_eq!(led["data"]["time"], 12.0);
+        assert_eq!(led["data"]["updated_at"], 3_000.0);
+        assert_eq!(led["seq"], 4);
+        assert_eq!(led["response_type"], "VideoAction");
+    }
+
+    #[test]
+    fn leaves_frames_without_sync_info_alone() {
+        assert!(lead_sync_frame(r#"{"response_type":"UserLeft","data":{}}"#, 3_000.0).is_none());
+        assert!(lead_sync_frame("not json", 3_000.0).is_none());
+    }
+
+    #[test]
+    fn clock_behind_the_last_update_never_rewinds() {
+        let current = extrapolate_sync_info(&sync_info(true, 0.5), 500.0);
 
-    room_sync_write.get(&room_id).cloned()
+        assert_eq!(current.time, 10.0);
+    }
 }
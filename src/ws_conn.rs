@@ -1,32 +1,71 @@
-use futures_util::SinkExt;
 use futures_util::stream::StreamExt;
-use mongodb::Database;
+use mongodb::bson::doc;
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
-use tokio_tungstenite::accept_async;
-use tokio_tungstenite::{tungstenite::Message as TokioMessage};
+use tokio::time::MissedTickBehavior;
+use tokio_tungstenite::accept_hdr_async;
+use tokio_tungstenite::tungstenite::Message as TokioMessage;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 
 use crate::actions::add_user::add_new_user;
-use crate::db::db::Message;
-use crate::services::message::{add_message, broadcast_message};
-use crate::services::video::set_sync_info;
-use crate::{RoomSync, RoomUserMap, config, ws_conn};
+use crate::actions::remove_user::remove_user;
+use crate::actions::roles::{get_room, set_playback_control, set_role, transfer_host};
+use crate::auth::{token_from_request, verify_session_token};
+use crate::db::db::{Message, PlaybackControl, Role, Room};
+use crate::services::dedupe::dedupe_key;
+use crate::services::message::{
+    AddMessageResponse, Delivery, MAX_HISTORY_LIMIT, MessageError, MessageHistoryResponse,
+    ReactionUpdate, add_message, broadcast_message, broadcast_message_to, broadcast_sync_update,
+    delete_message, edit_message, get_messages, set_reaction,
+};
+use crate::services::moderation::{ModerationAction, ModerationError, moderate_member};
+use crate::services::outbound::{ClientHandle, FrameKind};
+use crate::services::presence::{set_idle, start_typing, stop_typing};
+use crate::services::reaction::{VideoReactionError, VideoReactionResponse, add_video_reaction};
+use crate::services::reaper::mark_occupied;
+use crate::services::video::{
+    apply_video_action, cancel_auto_resume, get_sync_info_for_client, now_millis, report_buffering,
+    report_ready,
+};
+use crate::{AppState, config, ws_conn};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ActionType {
     Play,
     Pause,
     Skip,
+    Seek,
+    SetRate,
+    Buffering,
+    Ready,
     UserJoined,
     UserLeft,
     Unknown,
     Message,
+    SyncRequest,
+    TimeSync,
+    SetRole,
+    TransferHost,
+    SetPlaybackControl,
+    Kick,
+    Ban,
+    MessageEdit,
+    MessageDelete,
+    ReactionAdd,
+    ReactionRemove,
+    VideoReaction,
+    TypingStarted,
+    TypingStopped,
+    Idle,
+    Active,
+    Resume,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -34,6 +73,10 @@ pub enum VideoAction {
     Play,
     Pause,
     Skip,
+    Seek { to: f32 },
+    SetRate { rate: f32 },
+    Buffering,
+    Ready,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -41,7 +84,16 @@ pub struct SyncInfo {
     pub last_action: VideoAction,
     pub time: f32,
     pub updated_at: f64,
+    #[serde(default)]
     pub updated_by: String,
+    #[serde(default = "default_rate")]
+    pub rate: f32,
+    #[serde(default)]
+    pub playing: bool,
+}
+
+fn default_rate() -> f32 {
+    1.0
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -50,6 +102,9 @@ pub struct WebsocketEvent {
     pub room_id: Option<String>,
     pub user_id: Option<String>,
     pub payload: EventPayload,
+    // Opaque client id, echoed back in the Ack or Nack for this event
+    #[serde(default)]
+    pub request_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -59,26 +114,257 @@ pub enum EventPayload {
     UserLeft,
     VideoAction(SyncInfo),
     ChatMessage(MessageData),
+    SyncRequest,
+    TimeSync(TimeSyncRequest),
+    Buffering,
+    Ready,
+    RoleChange(RoleChangeData),
+    Member(MemberData),
+    PlaybackControl(PlaybackControl),
+    MessageEdit(MessageEditData),
+    MessageDelete(MessageDeleteData),
+    Reaction(ReactionData),
+    VideoReaction(VideoReactionData),
+    Presence,
+    Resume(ResumeData),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserJoinData {
+    #[serde(default)]
     pub user_id: String,
     pub room_id: String,
+    // Number of recent chat messages to send back after joining
+    #[serde(default)]
+    pub history: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageData {
+    #[serde(default)]
     pub user_id: String,
     pub room_id: String,
     pub message: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageEditData {
+    pub message_id: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageDeleteData {
+    pub message_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionData {
+    pub message_id: String,
+    pub emoji: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VideoReactionData {
+    pub emoji: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeData {
+    pub session_id: String,
+    // Highest seq the client applied before it lost the connection
+    pub last_seq: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeSyncRequest {
+    pub client_send_time: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeSyncResponse {
+    pub client_send_time: f64,
+    pub server_receive_time: f64,
+    pub server_send_time: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoleChangeData {
+    pub user_id: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberData {
+    pub user_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoleChangedData {
+    pub room_id: String,
+    pub user_id: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaybackControlChangedData {
+    pub room_id: String,
+    pub control: PlaybackControl,
+}
+
+// Lets the host and co-hosts act on a playback change a viewer was not allowed to make
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaybackRequestedData {
+    pub room_id: String,
+    pub user_id: String,
+    pub action: VideoAction,
+    pub time: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorData {
+    pub code: String,
+    pub message: String,
+    // Echoes the request id of the event that failed, if it carried one
+    pub request_id: Option<String>,
+}
+
+// What the server settled on for an acknowledged event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum AckResult {
+    Accepted,
+    Message(AddMessageResponse),
+    SyncInfo(SyncInfo),
+    Reaction(ReactionUpdate),
+    VideoReaction(VideoReactionResponse),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AckData {
+    pub request_id: String,
+    pub result: AckResult,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NackData {
+    pub request_id: String,
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WsError {
+    #[error("Malformed event: {0}")]
+    MalformedEvent(String),
+    #[error("Unknown action")]
+    UnknownAction,
+    #[error("Payload does not match the action")]
+    InvalidPayload,
+    #[error("No room given and the connection has not joined one")]
+    MissingRoom,
+    #[error("Invalid user id")]
+    InvalidUserId,
+    #[error("Room not found")]
+    RoomNotFound,
+    #[error("You are banned from this room")]
+    Banned,
+    #[error("Session expired or unknown, join the room again")]
+    UnknownSession,
+    #[error("{0}")]
+    Forbidden(&'static str),
+    #[error("{0}")]
+    InvalidRoleChange(&'static str),
+    #[error("{0}")]
+    InvalidVideoAction(String),
+    #[error("{0}")]
+    Internal(&'static str),
+    #[error(transparent)]
+    Moderation(#[from] ModerationError),
+    #[error(transparent)]
+    Message(#[from] MessageError),
+    #[error(transparent)]
+    VideoReaction(#[from] VideoReactionError),
+}
+
+impl WsError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            WsError::MalformedEvent(_) => "malformed_event",
+            WsError::UnknownAction => "unknown_action",
+            WsError::InvalidPayload => "invalid_payload",
+            WsError::MissingRoom => "missing_room_id",
+            WsError::InvalidUserId => "invalid_user_id",
+            WsError::RoomNotFound => "room_not_found",
+            WsError::Banned => "banned",
+            WsError::UnknownSession => "unknown_session",
+            WsError::Forbidden(_) => "forbidden",
+            WsError::InvalidRoleChange(_) => "invalid_role_change",
+            WsError::InvalidVideoAction(_) => "invalid_video_action",
+            WsError::Internal(_) => "internal_error",
+            WsError::Moderation(err) => err.code(),
+            WsError::Message(err) => err.code(),
+            WsError::VideoReaction(err) => err.code(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionData {
+    pub session_id: String,
+    pub room_id: String,
+    pub last_seq: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomSnapshot {
+    pub sync_info: Option<SyncInfo>,
+    pub history: Option<MessageHistoryResponse>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumedData {
+    pub session_id: String,
+    pub room_id: String,
+    pub last_seq: u64,
+    pub replayed: usize,
+    pub snapshot: Option<RoomSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserLeftData {
+    pub user_id: String,
+    pub room_id: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum WebsocketResponseType {
     VideoAction,
     Message,
     UserJoined,
+    UserLeft,
+    SyncInfo,
+    TimeSync,
+    RoleChanged,
+    RoleAssigned,
+    PlaybackControlChanged,
+    PlaybackRequested,
+    UserKicked,
+    UserBanned,
+    MessageHistory,
+    MessageEdited,
+    MessageDeleted,
+    ReactionUpdated,
+    VideoReaction,
+    TypingStarted,
+    TypingStopped,
+    Idle,
+    Active,
+    Ack,
+    Nack,
+    Session,
+    Resumed,
+    Error,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -95,14 +381,60 @@ impl FromStr for ActionType {
             "play" => Ok(ActionType::Play),
             "pause" => Ok(ActionType::Pause),
             "skip" => Ok(ActionType::Skip),
+            "seek" => Ok(ActionType::Seek),
+            "set_rate" => Ok(ActionType::SetRate),
+            "buffering" => Ok(ActionType::Buffering),
+            "ready" => Ok(ActionType::Ready),
             "user_joined" => Ok(ActionType::UserJoined),
             "user_left" => Ok(ActionType::UserLeft),
             "message" => Ok(ActionType::Message),
+            "sync_request" => Ok(ActionType::SyncRequest),
+            "time_sync" => Ok(ActionType::TimeSync),
+            "set_role" => Ok(ActionType::SetRole),
+            "transfer_host" => Ok(ActionType::TransferHost),
+            "set_playback_control" => Ok(ActionType::SetPlaybackControl),
+            "kick" => Ok(ActionType::Kick),
+            "ban" => Ok(ActionType::Ban),
+            "message_edit" => Ok(ActionType::MessageEdit),
+            "message_delete" => Ok(ActionType::MessageDelete),
+            "reaction_add" => Ok(ActionType::ReactionAdd),
+            "reaction_remove" => Ok(ActionType::ReactionRemove),
+            "video_reaction" => Ok(ActionType::VideoReaction),
+            "typing_started" => Ok(ActionType::TypingStarted),
+            "typing_stopped" => Ok(ActionType::TypingStopped),
+            "idle" => Ok(ActionType::Idle),
+            "active" => Ok(ActionType::Active),
+            "resume" => Ok(ActionType::Resume),
             _ => Ok(ActionType::Unknown),
         }
     }
 }
 
+impl ActionType {
+    // Actions that change room state when applied twice, only these are deduplicated by request id
+    pub fn is_mutation(&self) -> bool {
+        matches!(
+            self,
+            ActionType::Message
+                | ActionType::MessageEdit
+                | ActionType::MessageDelete
+                | ActionType::ReactionAdd
+                | ActionType::ReactionRemove
+                | ActionType::VideoReaction
+                | ActionType::Play
+                | ActionType::Pause
+                | ActionType::Skip
+                | ActionType::Seek
+                | ActionType::SetRate
+                | ActionType::SetRole
+                | ActionType::TransferHost
+                | ActionType::SetPlaybackControl
+                | ActionType::Kick
+                | ActionType::Ban
+        )
+    }
+}
+
 pub async fn create_websocket_connection() -> Result<TcpListener, anyhow::Error> {
     let port = config::Config::get_config().ws_port;
 
@@ -121,39 +453,113 @@ pub async fn create_websocket_connection() -> Result<TcpListener, anyhow::Error>
     Ok(server)
 }
 
-pub async fn handle_connection(
-    raw_stream: TcpStream,
-    addr: SocketAddr,
-    db: Database,
-    room_sync: RoomSync,
-    room_users_collection: RoomUserMap,
-) {
+// ErrorResponse is dictated by the tungstenite handshake callback
+#[allow(clippy::result_large_err)]
+pub async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr, app_state: AppState) {
     println!("Incoming TCP connection from: {:?}", addr);
 
-    // This handles the HTTP WebSocket upgrade automatically
-    let ws_stream = accept_async(raw_stream)
-        .await
-        .map_err(|e| {
+    let mut authenticated_user: Option<String> = None;
+
+    // This handles the HTTP WebSocket upgrade automatically, rejecting it without a valid session token
+    let handshake = accept_hdr_async(raw_stream, |req: &Request, resp: Response| {
+        let user_id = token_from_request(req)
+            .and_then(|token| verify_session_token(&token, &app_state.config.session_secret).ok());
+
+        match user_id {
+            Some(user_id) => {
+                authenticated_user = Some(user_id);
+                Ok(resp)
+            }
+            None => {
+                let mut error_response = ErrorResponse::new(Some("Invalid session token".into()));
+                *error_response.status_mut() = StatusCode::UNAUTHORIZED;
+                Err(error_response)
+            }
+        }
+    });
+
+    // A client that never finishes the upgrade would otherwise hold its connection permit forever
+    let handshake_timeout = Duration::from_secs(app_state.config.handshake_timeout_secs);
+
+    let ws_stream = match tokio::time::timeout(handshake_timeout, handshake).await {
+        Ok(Ok(ws_stream)) => ws_stream,
+        Ok(Err(e)) => {
             log::error!("WebSocket handshake error for {:?}: {:?}", addr, e);
-            anyhow::Error::msg(format!("Error during the websocket handshake: {}", e))
-        })
-        .expect("Failed to accept raw stream");
+            return;
+        }
+        Err(_) => {
+            log::warn!("WebSocket handshake timed out for: {:?}", addr);
+            return;
+        }
+    };
+
+    let Some(user_id) = authenticated_user else {
+        return;
+    };
 
-    log::info!("WebSocket connection established: {:?}", addr);
+    log::info!(
+        "WebSocket connection established: {:?} as user: {}",
+        addr,
+        user_id
+    );
 
     let (outgoing, mut incoming) = ws_stream.split();
-    let outgoing = Arc::new(RwLock::new(outgoing));
+    let outgoing = ClientHandle::spawn(
+        outgoing,
+        app_state.config.outbound_queue_size,
+        app_state.config.slow_consumer_policy,
+    );
 
-    while let Some(broadcast_message_option) = incoming.next().await {
-        let message = broadcast_message_option
-            .map_err(|e| {
+    // Room this connection is registered under, if any
+    let mut joined_room: Option<String> = None;
+    // Lets the client pick up where it left off if this socket drops
+    let mut session: Option<String> = None;
+
+    let heartbeat_period = Duration::from_secs(app_state.config.heartbeat_interval_secs.max(1));
+    // The first ping goes out right away, so a join already has a round trip estimate to lead by
+    let mut heartbeat = tokio::time::interval(heartbeat_period);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_pong = Instant::now();
+    // Round trips are timed here from our own pings, never taken from what the client reports
+    let mut ping_sent: Option<Instant> = None;
+
+    loop {
+        let broadcast_message_option = tokio::select! {
+            message = incoming.next() => message,
+            _ = outgoing.disconnected() => {
+                log::info!("Outbound side closed for: {:?}", addr);
+                break;
+            }
+            _ = heartbeat.tick() => {
+                // Half-open sockets never error on read, so silence is the only sign they are gone
+                if last_pong.elapsed() > heartbeat_period * app_state.config.heartbeat_max_missed {
+                    log::warn!("Missed heartbeats, dropping connection: {:?}", addr);
+                    outgoing.close();
+                    break;
+                }
+
+                ping_sent = Some(Instant::now());
+                outgoing.send(TokioMessage::Ping(Default::default()), FrameKind::Event);
+                continue;
+            }
+        };
+
+        let Some(broadcast_message_option) = broadcast_message_option else {
+            break;
+        };
+
+        let message = match broadcast_message_option {
+            Ok(message) => message,
+            Err(e) => {
                 log::error!(
                     "Failed to get broadcasted message. Failed with error: {:?}",
                     e
                 );
-                anyhow::Error::msg("Failed to get broadcasted message")
-            })
-            .unwrap();
+                break;
+            }
+        };
+
+        let received_at = now_millis();
 
         log::info!("Received a message from {}: {:?}", addr, message);
 
@@ -161,150 +567,1108 @@ pub async fn handle_connection(
             TokioMessage::Text(text) => {
                 let text = text.as_str();
                 log::info!("Text: {}", text);
-                match serde_json::from_str::<WebsocketEvent>(text) {
-                    Ok(websocket_event_details) => match websocket_event_details.action {
-                        ActionType::UserJoined => {
-                            if let ws_conn::EventPayload::UserJoined(user_data) =
-                                websocket_event_details.payload
-                            {
-                                let _user_info = add_new_user(
-                                    user_data.room_id.clone(),
-                                    ObjectId::parse_str(&user_data.user_id).unwrap(),
-                                    db.clone(),
-                                )
-                                .await
-                                .unwrap();
-
-                                let sync_status_write = room_sync.write().await;
-                                let sync_status = sync_status_write.get(&user_data.room_id);
-
-                                if let Some(status) = sync_status {
-                                    let mut write_outgoing = outgoing.write().await;
-
-                                    if let Err(error) = write_outgoing
-                                        .send(TokioMessage::Text(
-                                            serde_json::to_string(&WebsocketResponse {
-                                                response_type:
-                                                    ws_conn::WebsocketResponseType::UserJoined,
-                                                data: status,
-                                            })
-                                            .unwrap()
-                                            .into(),
-                                        ))
-                                        .await
-                                    {
-                                        log::error!(
-                                            "Failed to sync updates to the joined user. Failed with error: {:?}",
-                                            error
-                                        );
-                                    }
-
-                                    let mut write_users_connection =
-                                        room_users_collection.write().await;
-
-                                    let room_map = write_users_connection
-                                        .entry(user_data.room_id.clone())
-                                        .or_insert(HashMap::new());
-                                    room_map
-                                        .insert(user_data.user_id, outgoing.clone());
-                                }
-                            }
-                        }
-                        ActionType::Message => {
-                            if let ws_conn::EventPayload::ChatMessage(message_data) =
-                                websocket_event_details.payload
-                            {
-                                let message = Message {
-                                    user_id: ObjectId::parse_str(&message_data.user_id).unwrap(),
-                                    message: message_data.message,
-                                };
-
-                                match add_message(db.clone(), message_data.room_id.clone(), message)
-                                    .await
-                                {
-                                    Ok(result) => {
-                                        broadcast_message(
-                                            room_users_collection.clone(),
-                                            message_data.room_id,
-                                            tokio_tungstenite::tungstenite::Message::Text(
-                                                serde_json::to_string(&WebsocketResponse {
-                                                    response_type:
-                                                        ws_conn::WebsocketResponseType::Message,
-                                                    data: &result,
-                                                })
-                                                .unwrap()
-                                                .into(),
-                                            ),
-                                        )
-                                        .await;
-                                    }
-                                    Err(err) => {
-                                        log::error!(
-                                            "Failed to add message into the DB. Failed with error: {:?}",
-                                            err
-                                        )
-                                    }
-                                }
-                            }
-                        }
-                        ActionType::Play | ActionType::Pause | ActionType::Skip => {
-                            if let ws_conn::EventPayload::VideoAction(video_action_data) =
-                                websocket_event_details.payload
-                            {
-                                let room_id =  match websocket_event_details.room_id {
-                                    Some(id) => id,
-                                    None => {
-                                        log::error!("Error parsing room id.c");
-
-                                        continue;
-                                    }
-                                };
-                                let sync_info = SyncInfo {
-                                    last_action: video_action_data.last_action,
-                                    time: video_action_data.time,
-                                    updated_at: video_action_data.updated_at,
-                                    updated_by: video_action_data.updated_by,
-                                };
-
-                                set_sync_info(
-                                    room_id.clone(),
-                                    sync_info.clone(),
-                                    room_sync.clone(),
-                                )
-                                .await;
-
-                                broadcast_message(
-                                    room_users_collection.clone(),
-                                    room_id,
-                                    TokioMessage::Text(
-                                        serde_json::to_string(&WebsocketResponse {
-                                            response_type:
-                                                ws_conn::WebsocketResponseType::VideoAction,
-                                            data: &sync_info.clone(),
-                                        })
-                                        .unwrap()
-                                        .into(),
-                                    ),
-                                )
-                                .await;
-                            }
-                        }
-                        _ => {}
-                    },
+
+                let websocket_event_details = match serde_json::from_str::<WebsocketEvent>(text) {
+                    Ok(websocket_event_details) => websocket_event_details,
                     Err(err) => {
                         log::error!(
                             "Failed to parse websocket event. Failed with error: {:?}",
                             err
                         );
+
+                        send_error(
+                            &outgoing,
+                            &WsError::MalformedEvent(err.to_string()),
+                            raw_request_id(text),
+                        );
+
+                        continue;
                     }
+                };
+
+                let request_id = websocket_event_details.request_id.clone();
+
+                // Joins, resumes and reads are always handled again, a reconnecting client needs their side effects
+                let dedupe_key = request_id
+                    .as_deref()
+                    .filter(|_| websocket_event_details.action.is_mutation())
+                    .map(|request_id| dedupe_key(&user_id, request_id));
+
+                // A retried event is not applied twice, the client gets the original ack back
+                if let (Some(request_id), Some(key)) = (&request_id, &dedupe_key)
+                    && let Some(result) = app_state.dedupe.get(key).await
+                {
+                    send_response(
+                        &outgoing,
+                        WebsocketResponseType::Ack,
+                        &AckData {
+                            request_id: request_id.clone(),
+                            result,
+                        },
+                    );
+
+                    continue;
+                }
+
+                let result = handle_event(
+                    &app_state,
+                    &outgoing,
+                    &user_id,
+                    &mut joined_room,
+                    &mut session,
+                    websocket_event_details,
+                    received_at,
+                )
+                .await;
+
+                // Errors are reported to the client, the connection stays open
+                match (&result, &dedupe_key) {
+                    (Ok(result), Some(key)) => app_state.dedupe.set(key, result.clone()).await,
+                    (Err(err), _) => {
+                        log::error!(
+                            "Failed to handle event from user: {}. Failed with error: {:?}",
+                            user_id,
+                            err
+                        );
+                    }
+                    _ => {}
+                }
+
+                // Events without a request id keep the plain Error frame and get no ack
+                match (request_id, result) {
+                    (Some(request_id), Ok(result)) => send_response(
+                        &outgoing,
+                        WebsocketResponseType::Ack,
+                        &AckData { request_id, result },
+                    ),
+                    (Some(request_id), Err(err)) => send_response(
+                        &outgoing,
+                        WebsocketResponseType::Nack,
+                        &NackData {
+                            request_id,
+                            code: err.code().to_string(),
+                            message: err.to_string(),
+                        },
+                    ),
+                    (None, Ok(_)) => {}
+                    (None, Err(err)) => send_error(&outgoing, &err, None),
                 }
             }
             TokioMessage::Close(close) => {
-                log::info!("Connection closed: {:?}", close.unwrap())
+                log::info!("Connection closed: {:?}", close);
+
+                // A client closing on purpose is gone for good, the grace period is for dropped sockets
+                if close
+                    .is_some_and(|frame| matches!(frame.code, CloseCode::Normal | CloseCode::Away))
+                {
+                    leave_joined_room(
+                        &app_state,
+                        &outgoing,
+                        &user_id,
+                        &mut joined_room,
+                        &mut session,
+                    )
+                    .await;
+                }
+
+                break;
+            }
+            TokioMessage::Pong(_) => {
+                last_pong = Instant::now();
+
+                if let Some(sent) = ping_sent.take() {
+                    outgoing.record_rtt_sample(sent.elapsed().as_secs_f64() * 1000.0);
+                }
             }
+            // tungstenite answers pings on its own
+            TokioMessage::Ping(_) => {}
             _ => {
                 log::info!("Something went wrong");
             }
         }
     }
+
+    if let Some(room_id) = joined_room {
+        match session {
+            Some(session_id) => {
+                detach_member(room_id, user_id, session_id, &outgoing, &app_state).await
+            }
+            None => handle_user_left(room_id, user_id, &outgoing, &app_state).await,
+        }
+    }
+
+    log::info!("WebSocket connection terminated: {:?}", addr);
+}
+
+async fn handle_event(
+    app_state: &AppState,
+    outgoing: &ClientHandle,
+    user_id: &str,
+    joined_room: &mut Option<String>,
+    session: &mut Option<String>,
+    websocket_event_details: WebsocketEvent,
+    received_at: f64,
+) -> Result<AckResult, WsError> {
+    let result = match websocket_event_details.action {
+        ActionType::UserJoined => {
+            let EventPayload::UserJoined(user_data) = websocket_event_details.payload else {
+                return Err(WsError::InvalidPayload);
+            };
+
+            let user = parse_user_id(user_id)?;
+
+            leave_joined_room(app_state, outgoing, user_id, joined_room, session).await;
+
+            if is_banned(app_state, &user_data.room_id, user_id).await? {
+                return Err(WsError::Banned);
+            }
+
+            add_new_user(user_data.room_id.clone(), user, app_state.db.clone())
+                .await
+                .map_err(|_| WsError::Internal("Failed to join the room"))?;
+
+            let sync_status = get_sync_info_for_client(
+                user_data.room_id.clone(),
+                app_state.room_sync.clone(),
+                outgoing.rtt(),
+            )
+            .await;
+
+            if let Some(status) = &sync_status {
+                send_response(outgoing, WebsocketResponseType::UserJoined, status);
+            }
+
+            if let Some(history) = user_data.history.filter(|n| *n > 0) {
+                match get_messages(
+                    app_state.db.clone(),
+                    user_data.room_id.clone(),
+                    None,
+                    history.min(MAX_HISTORY_LIMIT),
+                )
+                .await
+                {
+                    Ok(Some(messages)) => {
+                        send_response(outgoing, WebsocketResponseType::MessageHistory, &messages);
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        log::error!(
+                            "Failed to load chat history for the joined user. Failed with error: {:?}",
+                            err
+                        );
+                    }
+                }
+            }
+
+            register_socket(app_state, &user_data.room_id, user_id, outgoing).await;
+
+            let session_id = app_state
+                .sessions
+                .start(&user_data.room_id, user_id, outgoing.id())
+                .await;
+
+            send_response(
+                outgoing,
+                WebsocketResponseType::Session,
+                &SessionData {
+                    session_id: session_id.clone(),
+                    room_id: user_data.room_id.clone(),
+                    last_seq: app_state.event_log.last_seq(&user_data.room_id).await,
+                },
+            );
+
+            *joined_room = Some(user_data.room_id);
+            *session = Some(session_id);
+
+            sync_status.map_or(AckResult::Accepted, AckResult::SyncInfo)
+        }
+        ActionType::UserLeft => {
+            leave_joined_room(app_state, outgoing, user_id, joined_room, session).await;
+
+            AckResult::Accepted
+        }
+        ActionType::Resume => {
+            let EventPayload::Resume(resume) = websocket_event_details.payload else {
+                return Err(WsError::InvalidPayload);
+            };
+
+            if session.as_deref() != Some(resume.session_id.as_str()) {
+                leave_joined_room(app_state, outgoing, user_id, joined_room, session).await;
+            }
+
+            let resumed = app_state
+                .sessions
+                .resume(&resume.session_id, user_id, outgoing.id())
+                .await
+                .ok_or(WsError::UnknownSession)?;
+
+            if let Err(err) = require_member(app_state, &resumed.room_id, user_id).await {
+                app_state
+                    .sessions
+                    .end(&resume.session_id, outgoing.id())
+                    .await;
+                return Err(err);
+            }
+
+            // Registering before reading the log means nothing falls in between, clients drop seqs they already have
+            register_socket(app_state, &resumed.room_id, user_id, outgoing).await;
+
+            let missed = app_state
+                .event_log
+                .since(&resumed.room_id, resume.last_seq)
+                .await;
+
+            let snapshot = match &missed {
+                Some(_) => None,
+                None => Some(room_snapshot(app_state, &resumed.room_id, outgoing).await),
+            };
+
+            let mut replayed = 0;
+
+            for event in missed.into_iter().flatten() {
+                if event.delivery.includes(user_id) {
+                    outgoing.send(TokioMessage::Text(event.payload.into()), FrameKind::Event);
+                    replayed += 1;
+                }
+            }
+
+            send_response(
+                outgoing,
+                WebsocketResponseType::Resumed,
+                &ResumedData {
+                    session_id: resume.session_id.clone(),
+                    room_id: resumed.room_id.clone(),
+                    last_seq: app_state.event_log.last_seq(&resumed.room_id).await,
+                    replayed,
+                    snapshot,
+                },
+            );
+
+            *joined_room = Some(resumed.room_id);
+            *session = Some(resume.session_id);
+
+            AckResult::Accepted
+        }
+        ActionType::Message => {
+            let EventPayload::ChatMessage(message_data) = websocket_event_details.payload else {
+                return Err(WsError::InvalidPayload);
+            };
+
+            let room_id = event_room(Some(message_data.room_id), joined_room)?;
+
+            let message = Message {
+                id: Some(ObjectId::new()),
+                room_id: room_id.clone(),
+                user_id: parse_user_id(user_id)?,
+                message: message_data.message,
+                created_at: mongodb::bson::DateTime::now(),
+                edited_at: None,
+                deleted_at: None,
+                reactions: HashMap::new(),
+            };
+
+            // Sending the message ends the sender's typing indicator
+            stop_typing(app_state, room_id.clone(), user_id.to_string()).await;
+
+            let result = add_message(app_state.db.clone(), message)
+                .await
+                .map_err(|_| WsError::Internal("Failed to save the message"))?;
+
+            broadcast_message(
+                app_state,
+                room_id,
+                tokio_tungstenite::tungstenite::Message::Text(
+                    serde_json::to_string(&WebsocketResponse {
+                        response_type: ws_conn::WebsocketResponseType::Message,
+                        data: &result,
+                    })
+                    .unwrap()
+                    .into(),
+                ),
+            )
+            .await;
+
+            AckResult::Message(result)
+        }
+        ActionType::Play
+        | ActionType::Pause
+        | ActionType::Skip
+        | ActionType::Seek
+        | ActionType::SetRate => {
+            let EventPayload::VideoAction(video_action_data) = websocket_event_details.payload
+            else {
+                return Err(WsError::InvalidPayload);
+            };
+
+            // Non finite positions serialize as null and would leave the stored sync state unreadable
+            let seek_to = match video_action_data.last_action {
+                VideoAction::Seek { to } => Some(to),
+                _ => None,
+            };
+
+            if let Some(position) = std::iter::once(video_action_data.time)
+                .chain(seek_to)
+                .find(|position| !position.is_finite() || *position < 0.0)
+            {
+                return Err(WsError::InvalidVideoAction(format!(
+                    "Invalid playback position: {}",
+                    position
+                )));
+            }
+
+            let room_id = event_room(websocket_event_details.room_id, joined_room)?;
+
+            if !can_control_playback(app_state, &room_id, user_id).await {
+                broadcast_message_to(
+                    app_state,
+                    room_id.clone(),
+                    TokioMessage::Text(
+                        serde_json::to_string(&WebsocketResponse {
+                            response_type: WebsocketResponseType::PlaybackRequested,
+                            data: &PlaybackRequestedData {
+                                room_id: room_id.clone(),
+                                user_id: user_id.to_string(),
+                                action: video_action_data.last_action,
+                                time: video_action_data.time,
+                            },
+                        })
+                        .unwrap()
+                        .into(),
+                    ),
+                    Delivery::Role(Role::CoHost),
+                )
+                .await;
+
+                return Err(WsError::Forbidden(
+                    "Only the host and co-hosts can control playback in this room",
+                ));
+            }
+
+            match video_action_data.last_action {
+                VideoAction::Buffering | VideoAction::Ready => {
+                    return Err(WsError::InvalidVideoAction(
+                        "Buffering state must be reported with its own action".to_string(),
+                    ));
+                }
+                VideoAction::SetRate { rate } if !rate.is_finite() || rate <= 0.0 => {
+                    return Err(WsError::InvalidVideoAction(format!(
+                        "Invalid playback rate: {}",
+                        rate
+                    )));
+                }
+                VideoAction::Play | VideoAction::Pause => {
+                    cancel_auto_resume(&room_id, app_state.room_buffering.clone()).await;
+                }
+                _ => {}
+            }
+
+            let sync_info = apply_video_action(
+                room_id.clone(),
+                video_action_data.last_action,
+                video_action_data.time,
+                user_id.to_string(),
+                app_state.room_sync.clone(),
+            )
+            .await;
+
+            broadcast_sync_info(app_state, room_id, &sync_info).await;
+
+            AckResult::SyncInfo(sync_info)
+        }
+        ActionType::Buffering | ActionType::Ready => {
+            let room_id = event_room(websocket_event_details.room_id, joined_room)?;
+
+            let sync_info = if matches!(websocket_event_details.action, ActionType::Buffering) {
+                report_buffering(
+                    room_id.clone(),
+                    user_id.to_string(),
+                    app_state.room_sync.clone(),
+                    app_state.room_buffering.clone(),
+                )
+                .await
+            } else {
+                report_ready(
+                    room_id.clone(),
+                    user_id.to_string(),
+                    app_state.room_sync.clone(),
+                    app_state.room_buffering.clone(),
+                )
+                .await
+            };
+
+            if let Some(sync_info) = &sync_info {
+                broadcast_sync_info(app_state, room_id, sync_info).await;
+            }
+
+            sync_info.map_or(AckResult::Accepted, AckResult::SyncInfo)
+        }
+        ActionType::SyncRequest => {
+            let room_id = event_room(websocket_event_details.room_id, joined_room)?;
+
+            let status =
+                get_sync_info_for_client(room_id, app_state.room_sync.clone(), outgoing.rtt())
+                    .await;
+
+            if let Some(status) = &status {
+                send_response(outgoing, WebsocketResponseType::SyncInfo, status);
+            }
+
+            status.map_or(AckResult::Accepted, AckResult::SyncInfo)
+        }
+        ActionType::TimeSync => {
+            let EventPayload::TimeSync(time_sync) = websocket_event_details.payload else {
+                return Err(WsError::InvalidPayload);
+            };
+
+            send_response(
+                outgoing,
+                WebsocketResponseType::TimeSync,
+                &TimeSyncResponse {
+                    client_send_time: time_sync.client_send_time,
+                    server_receive_time: received_at,
+                    server_send_time: now_millis(),
+                },
+            );
+
+            AckResult::Accepted
+        }
+        ActionType::SetRole | ActionType::TransferHost => {
+            let room_id = event_room(websocket_event_details.room_id, joined_room)?;
+
+            let role_change = match websocket_event_details.payload {
+                EventPayload::RoleChange(role_change) => role_change,
+                EventPayload::Member(member) => RoleChangeData {
+                    user_id: member.user_id,
+                    role: Role::Host,
+                },
+                _ => return Err(WsError::InvalidPayload),
+            };
+
+            if matches!(websocket_event_details.action, ActionType::SetRole)
+                && role_change.role == Role::Host
+            {
+                return Err(WsError::InvalidRoleChange(
+                    "Use TransferHost to hand over the host role",
+                ));
+            }
+
+            handle_role_change(app_state, room_id, user_id, role_change).await?;
+
+            AckResult::Accepted
+        }
+        ActionType::SetPlaybackControl => {
+            let EventPayload::PlaybackControl(control) = websocket_event_details.payload else {
+                return Err(WsError::InvalidPayload);
+            };
+
+            let room_id = event_room(websocket_event_details.room_id, joined_room)?;
+
+            handle_playback_control(app_state, room_id, user_id, control).await?;
+
+            AckResult::Accepted
+        }
+        ActionType::Kick | ActionType::Ban => {
+            let EventPayload::Member(member) = websocket_event_details.payload else {
+                return Err(WsError::InvalidPayload);
+            };
+
+            let room_id = event_room(websocket_event_details.room_id, joined_room)?;
+
+            let action = match websocket_event_details.action {
+                ActionType::Ban => ModerationAction::Ban,
+                _ => ModerationAction::Kick,
+            };
+
+            moderate_member(app_state, room_id, user_id, &member.user_id, action).await?;
+
+            AckResult::Accepted
+        }
+        ActionType::MessageEdit | ActionType::MessageDelete => {
+            let room_id = event_room(websocket_event_details.room_id, joined_room)?;
+            let actor = parse_user_id(user_id)?;
+
+            let (result, response_type) = match websocket_event_details.payload {
+                EventPayload::MessageEdit(edit) => (
+                    edit_message(
+                        app_state.db.clone(),
+                        &room_id,
+                        &edit.message_id,
+                        actor,
+                        edit.message,
+                    )
+                    .await,
+                    WebsocketResponseType::MessageEdited,
+                ),
+                EventPayload::MessageDelete(delete) => (
+                    delete_message(app_state.db.clone(), &room_id, &delete.message_id, actor).await,
+                    WebsocketResponseType::MessageDeleted,
+                ),
+                _ => return Err(WsError::InvalidPayload),
+            };
+
+            let message = result?;
+
+            broadcast_message(
+                app_state,
+                room_id,
+                TokioMessage::Text(
+                    serde_json::to_string(&WebsocketResponse {
+                        response_type,
+                        data: &message,
+                    })
+                    .unwrap()
+                    .into(),
+                ),
+            )
+            .await;
+
+            AckResult::Message(message)
+        }
+        ActionType::ReactionAdd | ActionType::ReactionRemove => {
+            let EventPayload::Reaction(reaction) = websocket_event_details.payload else {
+                return Err(WsError::InvalidPayload);
+            };
+
+            let room_id = event_room(websocket_event_details.room_id, joined_room)?;
+
+            let update = set_reaction(
+                app_state.db.clone(),
+                &room_id,
+                &reaction.message_id,
+                parse_user_id(user_id)?,
+                &reaction.emoji,
+                matches!(websocket_event_details.action, ActionType::ReactionAdd),
+            )
+            .await?;
+
+            broadcast_message(
+                app_state,
+                room_id,
+                TokioMessage::Text(
+                    serde_json::to_string(&WebsocketResponse {
+                        response_type: WebsocketResponseType::ReactionUpdated,
+                        data: &update,
+                    })
+                    .unwrap()
+                    .into(),
+                ),
+            )
+            .await;
+
+            AckResult::Reaction(update)
+        }
+        ActionType::VideoReaction => {
+            let EventPayload::VideoReaction(reaction) = websocket_event_details.payload else {
+                return Err(WsError::InvalidPayload);
+            };
+
+            let room_id = event_room(websocket_event_details.room_id, joined_room)?;
+
+            let reaction = add_video_reaction(
+                app_state.db.clone(),
+                app_state.room_sync.clone(),
+                room_id.clone(),
+                parse_user_id(user_id)?,
+                reaction.emoji,
+            )
+            .await?;
+
+            broadcast_message(
+                app_state,
+                room_id,
+                TokioMessage::Text(
+                    serde_json::to_string(&WebsocketResponse {
+                        response_type: WebsocketResponseType::VideoReaction,
+                        data: &reaction,
+                    })
+                    .unwrap()
+                    .into(),
+                ),
+            )
+            .await;
+
+            AckResult::VideoReaction(reaction)
+        }
+        ActionType::TypingStarted
+        | ActionType::TypingStopped
+        | ActionType::Idle
+        | ActionType::Active => {
+            let room_id = event_room(websocket_event_details.room_id, joined_room)?;
+            let user_id = user_id.to_string();
+
+            match websocket_event_details.action {
+                ActionType::TypingStarted => start_typing(app_state, room_id, user_id).await,
+                ActionType::TypingStopped => stop_typing(app_state, room_id, user_id).await,
+                ActionType::Idle => set_idle(app_state, room_id, user_id, true).await,
+                _ => set_idle(app_state, room_id, user_id, false).await,
+            }
+
+            AckResult::Accepted
+        }
+        ActionType::Unknown => return Err(WsError::UnknownAction),
+    };
+
+    Ok(result)
+}
+
+fn send_response<T: Serialize>(
+    outgoing: &ClientHandle,
+    response_type: WebsocketResponseType,
+    data: &T,
+) {
+    let queued = outgoing.send(
+        TokioMessage::Text(
+            serde_json::to_string(&WebsocketResponse {
+                response_type,
+                data,
+            })
+            .unwrap()
+            .into(),
+        ),
+        FrameKind::Event,
+    );
+
+    if !queued {
+        log::error!("Failed to queue response for the user");
+    }
+}
+
+fn send_error(outgoing: &ClientHandle, err: &WsError, request_id: Option<String>) {
+    send_response(
+        outgoing,
+        WebsocketResponseType::Error,
+        &ErrorData {
+            code: err.code().to_string(),
+            message: err.to_string(),
+            request_id,
+        },
+    );
+}
+
+// Best effort correlation for frames that did not parse as an event
+fn raw_request_id(text: &str) -> Option<String> {
+    serde_json::from_str::<serde_json::Value>(text)
+        .ok()?
+        .get("request_id")?
+        .as_str()
+        .map(|request_id| request_id.to_string())
+}
+
+// Room scoped events only ever apply to the room this connection joined, naming it is optional
+fn event_room(room_id: Option<String>, joined_room: &Option<String>) -> Result<String, WsError> {
+    let Some(joined) = joined_room else {
+        return Err(match room_id {
+            Some(_) => WsError::Forbidden("Join the room before sending events to it"),
+            None => WsError::MissingRoom,
+        });
+    };
+
+    if room_id.is_some_and(|room_id| room_id != *joined) {
+        return Err(WsError::Forbidden(
+            "Join the room before sending events to it",
+        ));
+    }
+
+    Ok(joined.clone())
+}
+
+fn parse_user_id(user_id: &str) -> Result<ObjectId, WsError> {
+    ObjectId::parse_str(user_id).map_err(|_| WsError::InvalidUserId)
+}
+
+async fn can_control_playback(app_state: &AppState, room_id: &str, user_id: &str) -> bool {
+    let Ok(user) = ObjectId::parse_str(user_id) else {
+        return false;
+    };
+
+    match get_room(room_id.to_string(), app_state.db.clone()).await {
+        Ok(room) => room.can_control_playback(&user),
+        Err(err) => {
+            log::error!("Failed to check playback permissions: {:?}", err);
+            false
+        }
+    }
+}
+
+// Fails closed, a room that cannot be read is not one we let anybody into
+async fn is_banned(app_state: &AppState, room_id: &str, user_id: &str) -> Result<bool, WsError> {
+    let user = parse_user_id(user_id)?;
+
+    let room = app_state
+        .db
+        .collection::<Room>("rooms")
+        .find_one(doc! { "room_id": room_id })
+        .await
+        .map_err(|e| {
+            log::error!("Failed to fetch room. Failed with error: {:?}", e);
+            WsError::Internal("Failed to fetch room")
+        })?
+        .ok_or(WsError::RoomNotFound)?;
+
+    Ok(room.banned.contains(&user))
+}
+
+// Resuming skips the join, so the member has to still be in the room
+async fn require_member(app_state: &AppState, room_id: &str, user_id: &str) -> Result<(), WsError> {
+    let user = parse_user_id(user_id)?;
+
+    let room = get_room(room_id.to_string(), app_state.db.clone())
+        .await
+        .map_err(|_| WsError::RoomNotFound)?;
+
+    if room.banned.contains(&user) {
+        return Err(WsError::Banned);
+    }
+
+    if !room.users.contains(&user) {
+        return Err(WsError::Forbidden(
+            "You are no longer a member of this room, join it again",
+        ));
+    }
+
+    Ok(())
+}
+
+// Loads the room and makes sure the acting user is its host
+async fn require_host(
+    app_state: &AppState,
+    room_id: &str,
+    user_id: &str,
+) -> Result<(Room, ObjectId), WsError> {
+    let actor = parse_user_id(user_id)?;
+
+    let room = get_room(room_id.to_string(), app_state.db.clone())
+        .await
+        .map_err(|_| WsError::RoomNotFound)?;
+
+    if room.role_of(&actor) != Role::Host {
+        return Err(WsError::Forbidden("Only the host can manage roles"));
+    }
+
+    Ok((room, actor))
+}
+
+async fn handle_role_change(
+    app_state: &AppState,
+    room_id: String,
+    user_id: &str,
+    role_change: RoleChangeData,
+) -> Result<(), WsError> {
+    let (room, actor) = require_host(app_state, &room_id, user_id).await?;
+
+    let target = parse_user_id(&role_change.user_id)?;
+
+    if target == actor {
+        return Err(WsError::InvalidRoleChange(
+            "The host cannot change their own role",
+        ));
+    }
+
+    if !room.users.contains(&target) || room.banned.contains(&target) {
+        return Err(WsError::InvalidRoleChange(
+            "Roles can only be given to current members of the room",
+        ));
+    }
+
+    let result = if role_change.role == Role::Host {
+        transfer_host(room_id.clone(), actor, target, app_state.db.clone()).await
+    } else {
+        set_role(
+            room_id.clone(),
+            target,
+            role_change.role,
+            app_state.db.clone(),
+        )
+        .await
+    };
+
+    if result.is_err() {
+        return Err(WsError::Internal("Failed to update member role"));
+    }
+
+    let mut changes = vec![RoleChangedData {
+        room_id: room_id.clone(),
+        user_id: role_change.user_id,
+        role: role_change.role,
+    }];
+
+    if role_change.role == Role::Host {
+        changes.push(RoleChangedData {
+            room_id: room_id.clone(),
+            user_id: user_id.to_string(),
+            role: Role::CoHost,
+        });
+    }
+
+    // The affected member gets a private notice, the rest of the room sees the change
+    for change in changes {
+        let target = change.user_id.clone();
+
+        for (response_type, delivery) in [
+            (
+                WebsocketResponseType::RoleAssigned,
+                Delivery::User(target.clone()),
+            ),
+            (WebsocketResponseType::RoleChanged, Delivery::Except(target)),
+        ] {
+            broadcast_message_to(
+                app_state,
+                room_id.clone(),
+                TokioMessage::Text(
+                    serde_json::to_string(&WebsocketResponse {
+                        response_type,
+                        data: &change,
+                    })
+                    .unwrap()
+                    .into(),
+                ),
+                delivery,
+            )
+            .await;
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_playback_control(
+    app_state: &AppState,
+    room_id: String,
+    user_id: &str,
+    control: PlaybackControl,
+) -> Result<(), WsError> {
+    require_host(app_state, &room_id, user_id).await?;
+
+    if set_playback_control(room_id.clone(), control, app_state.db.clone())
+        .await
+        .is_err()
+    {
+        return Err(WsError::Internal("Failed to update playback control"));
+    }
+
+    broadcast_message(
+        app_state,
+        room_id.clone(),
+        TokioMessage::Text(
+            serde_json::to_string(&WebsocketResponse {
+                response_type: WebsocketResponseType::PlaybackControlChanged,
+                data: &PlaybackControlChangedData { room_id, control },
+            })
+            .unwrap()
+            .into(),
+        ),
+    )
+    .await;
+
+    Ok(())
+}
+
+async fn broadcast_sync_info(app_state: &AppState, room_id: String, sync_info: &SyncInfo) {
+    broadcast_sync_update(
+        app_state,
+        room_id,
+        TokioMessage::Text(
+            serde_json::to_string(&WebsocketResponse {
+                response_type: WebsocketResponseType::VideoAction,
+                data: sync_info,
+            })
+            .unwrap()
+            .into(),
+        ),
+    )
+    .await;
+}
+
+async fn handle_user_left(
+    room_id: String,
+    user_id: String,
+    outgoing: &ClientHandle,
+    app_state: &AppState,
+) {
+    if !unregister_socket(app_state, &room_id, &user_id, outgoing).await {
+        return;
+    }
+
+    release_member(app_state, &room_id, &user_id).await;
+    announce_user_left(app_state, room_id, user_id).await;
+}
+
+// Leaves the room for good, used when the client asks to leave or moves to another room
+async fn leave_joined_room(
+    app_state: &AppState,
+    outgoing: &ClientHandle,
+    user_id: &str,
+    joined_room: &mut Option<String>,
+    session: &mut Option<String>,
+) {
+    if let Some(session_id) = session.take() {
+        app_state.sessions.end(&session_id, outgoing.id()).await;
+    }
+
+    if let Some(room_id) = joined_room.take() {
+        handle_user_left(room_id, user_id.to_string(), outgoing, app_state).await;
+    }
+}
+
+// The socket is gone but the member keeps their place until the grace period runs out
+async fn detach_member(
+    room_id: String,
+    user_id: String,
+    session_id: String,
+    outgoing: &ClientHandle,
+    app_state: &AppState,
+) {
+    if !unregister_socket(app_state, &room_id, &user_id, outgoing).await {
+        app_state.sessions.end(&session_id, outgoing.id()).await;
+        return;
+    }
+
+    release_member(app_state, &room_id, &user_id).await;
+
+    let Some(detached) = app_state.sessions.detach(&session_id, outgoing.id()).await else {
+        announce_user_left(app_state, room_id, user_id).await;
+        return;
+    };
+
+    let app_state = app_state.clone();
+    let grace = Duration::from_secs(app_state.config.session_grace_secs);
+
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+
+        // Resumed, or joined again with a fresh session, possibly on another instance
+        if !app_state.sessions.expire(&detached).await {
+            return;
+        }
+
+        announce_user_left(&app_state, room_id, user_id).await;
+    });
+}
+
+async fn register_socket(
+    app_state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    outgoing: &ClientHandle,
+) {
+    app_state
+        .room_users
+        .write()
+        .await
+        .entry(room_id.to_string())
+        .or_insert(HashMap::new())
+        .insert(user_id.to_string(), outgoing.clone());
+
+    // Don't wait for the next reaper tick, an instance ticking sooner could evict the room meanwhile
+    mark_occupied(app_state, room_id).await;
+}
+
+// Returns false when another socket has replaced this one or it was already removed
+async fn unregister_socket(
+    app_state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    outgoing: &ClientHandle,
+) -> bool {
+    let mut write_users_connection = app_state.room_users.write().await;
+
+    let Some(room_map) = write_users_connection.get_mut(room_id) else {
+        return false;
+    };
+
+    // The user may have reconnected on a newer socket which replaced this one
+    if room_map
+        .get(user_id)
+        .is_none_or(|client| client.id() != outgoing.id())
+    {
+        return false;
+    }
+
+    room_map.remove(user_id);
+
+    if room_map.is_empty() {
+        write_users_connection.remove(room_id);
+    }
+
+    true
+}
+
+// Clears state that must not wait for the member to come back
+async fn release_member(app_state: &AppState, room_id: &str, user_id: &str) {
+    stop_typing(app_state, room_id.to_string(), user_id.to_string()).await;
+
+    if let Some(sync_info) = report_ready(
+        room_id.to_string(),
+        user_id.to_string(),
+        app_state.room_sync.clone(),
+        app_state.room_buffering.clone(),
+    )
+    .await
+    {
+        broadcast_sync_info(app_state, room_id.to_string(), &sync_info).await;
+    }
+}
+
+async fn announce_user_left(app_state: &AppState, room_id: String, user_id: String) {
+    match ObjectId::parse_str(&user_id) {
+        Ok(user) => {
+            if let Err(err) = remove_user(room_id.clone(), user, app_state.db.clone()).await {
+                log::error!(
+                    "Failed to remove user from the DB room. Failed with error: {:?}",
+                    err
+                );
+            }
+        }
+        Err(err) => {
+            log::error!("Error parsing user id. Failed with error: {:?}", err);
+        }
+    }
+
+    broadcast_message(
+        app_state,
+        room_id.clone(),
+        TokioMessage::Text(
+            serde_json::to_string(&WebsocketResponse {
+                response_type: WebsocketResponseType::UserLeft,
+                data: &UserLeftData { user_id, room_id },
+            })
+            .unwrap()
+            .into(),
+        ),
+    )
+    .await;
+}
+
+// Sent instead of a replay when the event log no longer reaches back to the client's seq
+async fn room_snapshot(
+    app_state: &AppState,
+    room_id: &str,
+    outgoing: &ClientHandle,
+) -> RoomSnapshot {
+    let sync_info = get_sync_info_for_client(
+        room_id.to_string(),
+        app_state.room_sync.clone(),
+        outgoing.rtt(),
+    )
+    .await;
+
+    let history = match get_messages(
+        app_state.db.clone(),
+        room_id.to_string(),
+        None,
+        MAX_HISTORY_LIMIT,
+    )
+    .await
+    {
+        Ok(history) => history,
+        Err(err) => {
+            log::error!(
+                "Failed to load chat history for the snapshot. Failed with error: {:?}",
+                err
+            );
+            None
+        }
+    };
+
+    RoomSnapshot { sync_info, history }
 }